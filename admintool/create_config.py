@@ -38,6 +38,26 @@ def make_config():
     ntp_servers_f.close()
     f.write("ntp_servers = " + ntp_servers + "\n")
     f.write("buffer_size = 5\n")
+    f.write("fork_choice = \"lowest_proof\"\n")
+    f.write("finality_depth = 20\n")
+    f.write("clock = \"ntp\"\n")
+    f.write("[block_buffer]\n")
+    f.write("max_blocks = 1024\n")
+    f.write("max_bytes = 16777216\n")
+    f.write("max_per_peer = 256\n")
+    f.write("orphan_timeout = 60\n")
+    f.write("[rpc]\n")
+    f.write("enable = true\n")
+    f.write("listen_address = \"127.0.0.1\"\n")
+    f.write("port = " + str(int(port) + 1000) + "\n")
+    f.write("ws_enable = true\n")
+    f.write("ws_port = " + str(int(port) + 2000) + "\n")
+    f.write("[byzantine]\n")
+    f.write("behaviour = \"honest\"\n")
+    f.write("withhold_blocks = 3\n")
+    f.write("stale_depth = 2\n")
+    f.write("future_ticks = 100\n")
+    f.write("orphans = 16\n")
     ids=range(size)
     ip_list = zip(ids, ip_list)
     del ip_list[nid]
@@ -1,9 +1,13 @@
 #!/bin/bash
+# usage: byzantinetest.sh [behaviour]
+# node3 runs with the given behaviour, see `Behaviour` in util/src/config.rs.
 set +e
 CUR_PATH=$(cd `dirname $0`; pwd)
+BEHAVIOUR=${1:-honest}
 cd ${CUR_PATH}/admintool/
 ./setup.sh
 ./admintool.sh
+sed -i "s/^behaviour = .*/behaviour = \"${BEHAVIOUR}\"/" ${CUR_PATH}/admintool/release/node3/config
 
 start_node() {
     id=$1
@@ -21,7 +25,7 @@ start_all () {
 echo "###start nodes..."
 start_all
 echo `date`
-echo "###Sleepy start OK"
+echo "###Sleepy start OK, node3 is ${BEHAVIOUR}"
 
 sleep 10
 echo "###Start random network delay"
@@ -8,13 +8,23 @@ use transaction::SignedTransaction;
 use bls;
 use rlp::*;
 use bytes::Bytes;
+use serde::{Deserialize, Deserializer};
 
 pub type BlockNumber = u64;
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Eq)]
+#[derive(Debug, PartialEq, Serialize, Clone, Eq)]
 pub struct HashWrap(Cell<Option<H256>>);
 
 unsafe impl Sync for HashWrap {}
 
+/// The cached hash is read and dropped, it is recomputed from the header
+/// rather than trusted from the peer.
+impl<'de> Deserialize<'de> for HashWrap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Option::<H256>::deserialize(deserializer)?;
+        Ok(HashWrap(Cell::new(None)))
+    }
+}
+
 impl Deref for HashWrap {
     type Target = Cell<Option<H256>>;
 
@@ -81,18 +91,29 @@ impl Header {
     }
 
     /// Get the hash of this header.
+    ///
+    /// The block signature signs this hash, so it is left out. Headers
+    /// stored by older builds may keep the signed header hash, see `RichHeader`.
     pub fn hash(&self) -> H256 {
         let hash = self.hash.get();
         match hash {
             Some(h) => h,
             None => {
-                let h = self.rlp_hash();
+                let h = self.bare_hash();
                 self.hash.set(Some(h.clone()));
                 h
             }
         }
     }
 
+    /// Check the cached hash against the header contents, for headers from peers.
+    pub fn check_hash(&self) -> Result<(), Error> {
+        if self.hash() != self.bare_hash() {
+            return Err(Error::InvalidFormat);
+        }
+        Ok(())
+    }
+
     ///if is genesis
     pub fn is_genesis(&self) -> Result<bool, Error> {
         if self.height == 0 {
@@ -129,6 +150,16 @@ impl Header {
     pub fn rlp_hash(&self) -> H256 {
         self.rlp().sha3()
     }
+
+    /// Get the hash of this header without the block signature.
+    pub fn bare_hash(&self) -> H256 {
+        if self.proof.block_signature.is_zero() {
+            return self.rlp_hash();
+        }
+        let mut header = self.clone();
+        header.proof.block_signature = H520::default();
+        header.rlp_hash()
+    }
 }
 
 impl Decodable for Header {
@@ -141,7 +172,7 @@ impl Decodable for Header {
             state_root: r.val_at(4)?,
             receipts_root: r.val_at(5)?,
             proof: r.val_at(6)?,
-            hash: HashWrap(Cell::new(Some(r.as_raw().sha3()))),
+            hash: HashWrap(Cell::new(None)),
         };
 
         Ok(blockheader)
@@ -160,12 +191,44 @@ impl HeapSizeOf for Header {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, RlpEncodable, RlpDecodable)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct RichHeader {
     pub header: Header,
     pub verified: bool,
 }
 
+/// Blocks stored by older builds may be identified by the hash of the signed
+/// header, that hash follows `verified` so they keep it.
+impl Encodable for RichHeader {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        let hash = self.header.hash();
+        let legacy = hash != self.header.bare_hash() && hash == self.header.rlp_hash();
+        s.begin_list(if legacy { 3 } else { 2 });
+        s.append(&self.header);
+        s.append(&self.verified);
+        if legacy {
+            s.append(&hash);
+        }
+    }
+}
+
+impl Decodable for RichHeader {
+    fn decode(r: &UntrustedRlp) -> Result<Self, DecoderError> {
+        let header: Header = r.val_at(0)?;
+        if r.item_count()? > 2 {
+            let hash: H256 = r.val_at(2)?;
+            if hash != header.rlp_hash() {
+                return Err(DecoderError::Custom("header hash mismatch"));
+            }
+            header.hash.set(Some(hash));
+        }
+        Ok(RichHeader {
+            header: header,
+            verified: r.val_at(1)?,
+        })
+    }
+}
+
 impl Deref for RichHeader {
     type Target = Header;
 
@@ -211,6 +274,11 @@ impl Body {
     pub fn transactions_root(&self) -> H256 {
         complete_merkle_root_raw(self.transactions.iter().map(|r| r.hash()).collect())
     }
+
+    ///generate the merkle branch of the transaction at index
+    pub fn transaction_proof(&self, index: usize) -> Option<Vec<ProofNode>> {
+        complete_merkle_proof_raw(self.transactions.iter().map(|r| r.hash()).collect(), index)
+    }
 }
 
 #[derive(Hash, Clone, Serialize, Deserialize, PartialEq, Eq, Debug, RlpEncodable, RlpDecodable)]
@@ -273,21 +341,21 @@ impl Block {
             block_signature: H520::default(),
         };
 
+        let body = Body {
+            transactions: transactions,
+        };
+
         let header = Header {
             parent_hash: parent_hash,
             timestamp: timestamp,
             height: height,
-            transactions_root: SHA3_NULL_RLP,
+            transactions_root: body.transactions_root(),
             state_root: SHA3_NULL_RLP,
             receipts_root: SHA3_NULL_RLP,
             hash: HashWrap(Cell::new(None)),
             proof: proof,
         };
 
-        let body = Body {
-            transactions: transactions,
-        };
-
         Block {
             header: header,
             body: body,
@@ -303,7 +371,18 @@ impl Block {
     /// Recovers the public key of the signer.
     pub fn sign_public(&self) -> Result<H512, Error> {
         let sig: Signature = self.proof.block_signature.into();
-        recover(&sig, &self.hash()).map_err(|_| Error::InvalidSignature)
+        recover(&sig, &self.bare_hash()).map_err(|_| Error::InvalidSignature)
+    }
+
+    /// Check the transaction hashes and the transactions root they commit to.
+    pub fn check_transactions_root(&self) -> Result<(), Error> {
+        if self.body.transactions.iter().any(|tx| tx.hash() != tx.cal_hash()) {
+            return Err(Error::InvalidTransactionHash);
+        }
+        if self.transactions_root != self.body.transactions_root() {
+            return Err(Error::InvalidTransactionsRoot);
+        }
+        Ok(())
     }
 
     /// Generate the genesis block.
@@ -350,4 +429,15 @@ mod tests {
         block.sign(&private_key);
         assert_eq!(block.sign_public().unwrap(), *keypair.pubkey());
     }
+
+    #[test]
+    fn decoded_header_keeps_hash() {
+        let mut block = Block::init(1, 12345, H256::default(), Vec::new(), vec![1, 2, 3]);
+        let private_key = H256::from("40f2d8f8e1594579824fd04edfc7ff1ddffd6be153b23f4318e1acff037d3ea9");
+        block.sign(&private_key);
+        let decoded: Header = decode(&block.header.rlp());
+        assert_eq!(decoded.hash(), block.hash());
+        assert_eq!(decoded, block.header);
+        assert!(decoded.sign_public().is_ok());
+    }
 }
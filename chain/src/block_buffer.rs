@@ -0,0 +1,216 @@
+//! Bounded buffer of blocks waiting to be imported.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+use util::H256;
+use util::config::BlockBufferConfig;
+use block::Block;
+use rlp;
+
+/// Id of the peer a block came from.
+pub type PeerId = u32;
+
+struct Entry {
+    block: Block,
+    origin: Option<PeerId>,
+    size: usize,
+    since: Instant,
+}
+
+/// Counters of a buffer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferStats {
+    /// Number of blocks in the buffer.
+    pub blocks: usize,
+    /// Encoded size of the blocks in the buffer.
+    pub bytes: usize,
+    /// Blocks dropped to make room, by count, size or peer limit.
+    pub evicted: u64,
+    /// Blocks dropped because they stayed longer than the timeout.
+    pub expired: u64,
+}
+
+/// Blocks kept in arrival order, oldest evicted first.
+pub struct BlockBuffer {
+    config: BlockBufferConfig,
+    timeout: Option<Duration>,
+    entries: BTreeMap<u64, Entry>,
+    hashes: HashSet<H256>,
+    per_peer: HashMap<PeerId, usize>,
+    next: u64,
+    stats: BufferStats,
+}
+
+impl BlockBuffer {
+    /// Create a buffer, blocks older than `timeout` are dropped by `expire`.
+    pub fn new(config: BlockBufferConfig, timeout: Option<Duration>) -> Self {
+        BlockBuffer {
+            config: config,
+            timeout: timeout,
+            entries: BTreeMap::new(),
+            hashes: HashSet::new(),
+            per_peer: HashMap::new(),
+            next: 0,
+            stats: BufferStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        self.stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    /// Add a block, evicting the oldest ones if a limit is hit.
+    pub fn push(&mut self, block: Block, origin: Option<PeerId>) {
+        let hash = block.hash();
+        if self.hashes.contains(&hash) {
+            return;
+        }
+
+        let size = encoded_size(&block);
+        if size > self.config.max_bytes || self.config.max_blocks == 0 {
+            self.stats.evicted += 1;
+            return;
+        }
+
+        if let Some(peer) = origin {
+            if self.per_peer.get(&peer).map_or(false, |n| *n >= self.config.max_per_peer) {
+                let oldest = self.entries.iter().find(|&(_, e)| e.origin == Some(peer)).map(|(k, _)| *k);
+                if let Some(key) = oldest {
+                    self.remove(key);
+                    self.stats.evicted += 1;
+                }
+            }
+        }
+
+        while self.entries.len() >= self.config.max_blocks || self.stats.bytes + size > self.config.max_bytes {
+            let oldest = *self.entries.keys().next().expect("limits are hit so buffer is not empty");
+            self.remove(oldest);
+            self.stats.evicted += 1;
+        }
+
+        if let Some(peer) = origin {
+            *self.per_peer.entry(peer).or_insert(0) += 1;
+        }
+        self.hashes.insert(hash);
+        self.stats.bytes += size;
+        self.stats.blocks += 1;
+        self.entries.insert(self.next, Entry {
+            block: block,
+            origin: origin,
+            size: size,
+            since: Instant::now(),
+        });
+        self.next += 1;
+    }
+
+    /// Remove and return the blocks matching `f`, oldest first.
+    pub fn take<F>(&mut self, f: F) -> Vec<(Block, Option<PeerId>)>
+        where F: Fn(&Block) -> bool
+    {
+        let keys: Vec<u64> = self.entries.iter().filter(|&(_, e)| f(&e.block)).map(|(k, _)| *k).collect();
+        keys.into_iter().filter_map(|k| self.remove(k)).map(|e| (e.block, e.origin)).collect()
+    }
+
+    /// Drop the blocks that stayed longer than the timeout.
+    pub fn expire(&mut self) {
+        let timeout = match self.timeout {
+            Some(t) => t,
+            None => return,
+        };
+        let keys: Vec<u64> = self.entries.iter()
+            .take_while(|&(_, e)| e.since.elapsed() >= timeout)
+            .map(|(k, _)| *k)
+            .collect();
+        for key in keys {
+            if let Some(e) = self.remove(key) {
+                trace!("drop buffered block {:?} {:?}", e.block.height, e.block.hash());
+                self.stats.expired += 1;
+            }
+        }
+    }
+
+    fn remove(&mut self, key: u64) -> Option<Entry> {
+        let entry = self.entries.remove(&key)?;
+        self.hashes.remove(&entry.block.hash());
+        self.stats.bytes -= entry.size;
+        self.stats.blocks -= 1;
+        if let Some(peer) = entry.origin {
+            let empty = {
+                let n = self.per_peer.get_mut(&peer).expect("buffered block counted for its peer");
+                *n -= 1;
+                *n == 0
+            };
+            if empty {
+                self.per_peer.remove(&peer);
+            }
+        }
+        Some(entry)
+    }
+}
+
+fn encoded_size(block: &Block) -> usize {
+    block.header.rlp().len() + rlp::encode(&block.body).len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(t: u64) -> Block {
+        Block::init(1, t, H256::default(), Vec::new(), Vec::new())
+    }
+
+    fn config(max_blocks: usize, max_per_peer: usize) -> BlockBufferConfig {
+        BlockBufferConfig {
+            max_blocks: max_blocks,
+            max_bytes: 1 << 20,
+            max_per_peer: max_per_peer,
+            orphan_timeout: 0,
+        }
+    }
+
+    #[test]
+    fn evicts_oldest_and_per_peer() {
+        let mut buffer = BlockBuffer::new(config(3, 2), None);
+        buffer.push(block(1), Some(1));
+        buffer.push(block(1), Some(1));
+        buffer.push(block(2), Some(1));
+        buffer.push(block(3), Some(1));
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.stats().evicted, 1);
+        assert!(!buffer.contains(&block(1).hash()));
+
+        buffer.push(block(4), Some(2));
+        buffer.push(block(5), None);
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.stats().evicted, 2);
+        assert!(!buffer.contains(&block(2).hash()));
+
+        let taken = buffer.take(|b| b.timestamp >= 4);
+        assert_eq!(taken.iter().map(|&(ref b, o)| (b.timestamp, o)).collect::<Vec<_>>(), vec![(4, Some(2)), (5, None)]);
+        assert_eq!(buffer.stats().blocks, 1);
+        assert_eq!(buffer.stats().bytes, encoded_size(&block(3)));
+    }
+
+    #[test]
+    fn expires_old_blocks() {
+        let mut buffer = BlockBuffer::new(config(3, 3), Some(Duration::from_secs(0)));
+        buffer.push(block(1), Some(1));
+        buffer.expire();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.stats().expired, 1);
+        assert_eq!(buffer.stats().evicted, 0);
+    }
+}
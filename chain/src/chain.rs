@@ -1,22 +1,33 @@
 use parking_lot::{Mutex, RwLock};
 use util::hash::H256;
-use util::Hashable;
+use util::{Address, Bytes, Hashable, ProofNode};
 use std::collections::{HashMap, HashSet, VecDeque};
-use rand::{thread_rng, Rng};
 use util::config::SleepyConfig;
+use util::clock::{self, Clock};
 use std::sync::mpsc::{Sender, channel};
 use std::thread;
 use std::sync::Arc;
 use std::time::Duration;
-use block::{Block, Body, RichHeader, BlockNumber};
+use block::{Block, Body, Header, RichHeader, BlockNumber};
 use transaction::SignedTransaction;
 use error::*;
 use kvdb::{DBTransaction, KeyValueDB};
 use cache_manager::CacheManager;
 use extras::*;
-use db::{self, Writable, Readable, CacheUpdatePolicy};
+use db::{self, Writable, Readable};
 use cache::*;
 use heapsize::HeapSizeOf;
+use state::{Account, State};
+use receipt::{Receipt, BlockReceipts};
+use fork_choice::{self, ForkChoice};
+use consensus::{Consensus, BlsLottery};
+use validator::{self, ValidatorSet};
+use block_buffer::{BlockBuffer, BufferStats, PeerId};
+use event::{ChainEvent, EventBus};
+use equivocation::Equivocation;
+use repair;
+use rlp;
+use std::sync::mpsc::Receiver;
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 enum CacheId {
@@ -24,6 +35,7 @@ enum CacheId {
     BlockBody(H256),
     BlockHashes(BlockNumber),
     TransactionAddresses(H256),
+    BlockReceipts(H256),
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -153,6 +165,16 @@ impl HashCache {
     }
 }
 
+/// Cache changes of a batch, applied once the batch is written.
+#[derive(Default)]
+struct CacheUpdate {
+    headers: Vec<RichHeader>,
+    block_hashes: Vec<(BlockNumber, H256)>,
+    removed_transactions: Vec<H256>,
+    transaction_addresses: Vec<(H256, TransactionAddress)>,
+    txs_cache: Option<HashCache>,
+}
+
 pub struct Chain {
     db: Arc<KeyValueDB>,
     cache_man: Mutex<CacheManager<CacheId>>,
@@ -164,54 +186,148 @@ pub struct Chain {
     //extra caches
     transaction_addresses: RwLock<HashMap<H256, TransactionAddress>>,
     block_hashes: RwLock<HashMap<BlockNumber,H256>>,
+    block_receipts: RwLock<HashMap<H256, BlockReceipts>>,
 
-    future_blocks: RwLock<Vec<Block>>,
-    unknown_parent: RwLock<HashMap<H256, Vec<Block>>>,
+    future_blocks: RwLock<BlockBuffer>,
+    unknown_parent: RwLock<BlockBuffer>,
     current_height: RwLock<u64>,
     current_hash: RwLock<H256>,
+    finalized_height: RwLock<u64>,
+    finalized_hash: RwLock<H256>,
 
     txs_cache: RwLock<HashCache>,
 
     config: Arc<RwLock<SleepyConfig>>,
+    fork_choice: Box<ForkChoice>,
+    consensus: Box<Consensus>,
+    clock: Arc<Clock>,
+    // imported blocks whose buffered children are inserted next
     sender: Mutex<Sender<H256>>,
+    imported: Mutex<Receiver<H256>>,
+    events: EventBus,
+    // reorgs found while switching, sent once the batch is written
+    pending_events: Mutex<Vec<ChainEvent>>,
+    equivocations: RwLock<Vec<Equivocation>>,
+    // held from the checks of a block until it is written and published
+    insert_lock: Mutex<()>,
+}
+
+/// Blocks back in which a transaction may not appear again.
+pub const TX_WINDOW: u64 = 100;
+
+/// Transactions must be newer than this, given the timestamp of the oldest
+/// block of the duplicate window.
+pub fn tx_left_bound(timestamp: u64, nps: u64) -> u64 {
+    timestamp * 1000 / nps
+}
+
+/// Build the genesis block and its state, the validator set comes from the config keygroups.
+pub fn genesis(config: &SleepyConfig, db: Arc<KeyValueDB>) -> (Block, State) {
+    let mut genesis = Block::genesis(config.start_time());
+    let keygroups = config.get_keygroups().clone();
+    let mut state = State::from_existing(db, genesis.state_root).expect("empty state not found!");
+    if !keygroups.is_empty() {
+        state.init_validators(&ValidatorSet::from(keygroups)).expect("init validators failed!");
+    }
+    genesis.state_root = state.root();
+    (genesis, state)
 }
 
 //TODO use more efficient  way to check duplicated transactions.
 
 impl Chain {
-    pub fn init(config: Arc<RwLock<SleepyConfig>>, db: Arc<KeyValueDB>) -> Arc<Self> {
+    pub fn init(config: Arc<RwLock<SleepyConfig>>, db: Arc<KeyValueDB>) -> Result<Arc<Self>, Error> {
+        let consensus = Box::new(BlsLottery::new(config.clone()));
+        Chain::init_with_consensus(config, db, consensus)
+    }
+
+    /// Init the chain with another consensus engine than the default BLS lottery.
+    pub fn init_with_consensus(config: Arc<RwLock<SleepyConfig>>, db: Arc<KeyValueDB>, consensus: Box<Consensus>) -> Result<Arc<Self>, Error> {
+        let clock = clock::from_config(&config.read());
+        Chain::init_with(config, db, consensus, clock)
+    }
+
+    /// Init the chain with given consensus engine and clock.
+    pub fn init_with(config: Arc<RwLock<SleepyConfig>>, db: Arc<KeyValueDB>, consensus: Box<Consensus>, clock: Arc<Clock>) -> Result<Arc<Self>, Error> {
+        let chain = Chain::init_driven(config, db, consensus, clock)?;
+
+        let mario = chain.clone();
+        thread::spawn(move || loop {
+                let hash = match mario.imported.lock().recv() {
+                    Ok(h) => h,
+                    Err(_) => break,
+                };
+                mario.insert_children(hash);
+        });
+
+        let subtask = chain.clone();
+        thread::spawn(move || {
+            info!("hanle pending!");
+            let dur = { 1000 / subtask.config.read().nps };
+            let dur = Duration::from_millis(dur);
+            loop {
+                thread::sleep(dur);
+                subtask.handle_pending();
+            }
+        });
+        Ok(chain)
+    }
+
+    /// Like `init_with`, without the threads inserting buffered blocks, the
+    /// caller drives them with `handle_orphans` and `handle_pending`.
+    ///
+    /// Fails if the stored best chain can't be loaded, `--repair` may fix it.
+    pub fn init_driven(config: Arc<RwLock<SleepyConfig>>, db: Arc<KeyValueDB>, consensus: Box<Consensus>, clock: Arc<Clock>) -> Result<Arc<Self>, Error> {
         let (sender, receiver) = channel();
         // 400 is the avarage size of the key
         let cache_man = CacheManager::new(1 << 14, 1 << 20, 400);
-        let lmt = 100u64;
+        let lmt = TX_WINDOW;
         let bs = {config.read().buffer_size};
+        let fork_choice = fork_choice::from_rule({config.read().fork_choice});
+        let buffer_config = {config.read().block_buffer.clone()};
+        let orphan_timeout = Duration::from_secs(buffer_config.orphan_timeout);
        
         let chain = Arc::new(Chain {
                                 db: db.clone(),
                                 cache_man: Mutex::new(cache_man),
                                 block_headers: RwLock::new(HashMap::new()),
                                 block_bodies: RwLock::new(HashMap::new()),
-                                future_blocks: RwLock::new(Vec::new()),
-                                unknown_parent: RwLock::new(HashMap::new()),
+                                future_blocks: RwLock::new(BlockBuffer::new(buffer_config.clone(), None)),
+                                unknown_parent: RwLock::new(BlockBuffer::new(buffer_config, Some(orphan_timeout))),
                                 transaction_addresses: RwLock::new(HashMap::new()),
                                 block_hashes: RwLock::new(HashMap::new()),
+                                block_receipts: RwLock::new(HashMap::new()),
                                 current_height: RwLock::new(0),
                                 current_hash: RwLock::new(H256::default()),
+                                finalized_height: RwLock::new(0),
+                                finalized_hash: RwLock::new(H256::default()),
 
                                 txs_cache: RwLock::new(HashCache::new((lmt+bs+5) as usize, (lmt+bs+1) as usize)),
 
                                 config: config,
+                                fork_choice: fork_choice,
+                                consensus: consensus,
+                                clock: clock,
                                 sender: Mutex::new(sender),
+                                imported: Mutex::new(receiver),
+                                events: EventBus::new(),
+                                pending_events: Mutex::new(Vec::new()),
+                                equivocations: RwLock::new(Vec::new()),
+                                insert_lock: Mutex::new(()),
                              });
 
-        let ret = chain.db.get(db::COL_EXTRA, b"current_hash").unwrap();
-        
+        if let Some(evidence) = chain.db.get(db::COL_EXTRA, b"equivocations").map_err(Error::Database)? {
+            *chain.equivocations.write() = rlp::decode_list(&evidence);
+        }
+
+        let depth = {chain.config.read().finality_depth};
+        let ret = repair::check_head(&*chain.db, lmt + bs, depth)?;
+
         match ret {
-            Some(hash) => {
+            Some((_, hash)) => {
                 let mut txs_cache = chain.txs_cache.write();
-                let hash = H256::from_slice(&hash);
                 info!("{}", hash);
-                let mut header = chain.get_block_header_by_hash(&hash).expect("header not found!");
+                let mut header = chain.get_block_header_by_hash(&hash).ok_or(Error::UnknownAncestor)?;
                 let mut current_height = chain.current_height.write();
                 let mut current_hash = chain.current_hash.write();
                 
@@ -228,122 +344,360 @@ impl Chain {
                         break;
                     }
 
-                    header = chain.get_block_header_by_hash(&header.parent_hash).expect("header not found!");
+                    header = chain.get_block_header_by_hash(&header.parent_hash).ok_or(Error::UnknownAncestor)?;
                 }
 
                 for _ in 0..n {
                     txs_cache.push_back(BlockInfo{hash: header.hash(), height: 0, timestamp: header.timestamp, transactions: Vec::new()});
                 }
+
+                let finalized = chain.db.get(db::COL_EXTRA, b"finalized_hash").map_err(Error::Database)?.map(|h| H256::from_slice(&h));
+                let finalized = match finalized {
+                    Some(h) => h,
+                    None => chain.block_hash_by_number_db(0).ok_or(Error::UnknownAncestor)?,
+                };
+                let finalized_header = chain.get_block_header_by_hash(&finalized).ok_or(Error::UnknownAncestor)?;
+                *chain.finalized_height.write() = finalized_header.height;
+                *chain.finalized_hash.write() = finalized;
             }
             None => {
                 let t = chain.config.read().start_time();
-                let genesis = Block::genesis(t);
+                let (genesis, state) = genesis(&chain.config.read(), chain.db.clone());
                 {
                     let mut txs_cache = chain.txs_cache.write();
                     for _ in 0..(lmt+bs+1) {
                         txs_cache.push_back(BlockInfo{hash: genesis.hash(), height: 0, timestamp: t, transactions: Vec::new()});
                     }
                 }
-                chain.insert_at(genesis, true);
+                *chain.finalized_hash.write() = genesis.hash();
+                chain.insert_at(genesis, true, Some(state), BlockReceipts::default())?;
             }
 
         }
+        Ok(chain)
+    }
 
-        let mario = chain.clone();
-        thread::spawn(move || loop {
-                let hash = receiver.recv().unwrap();
-                if let Some(blocks) = mario.unknown_parent.write().remove(&hash) {
-                    for b in blocks {
-                        let _ = mario.insert(b);                            
-                    }
-                }
-        });
+    /// Put the new best block in the batch, returns the new final block if it moved.
+    ///
+    /// The in-memory status is only changed by `set_status` once the batch is written,
+    /// the final block is looked up in `update` first.
+    fn save_status(&self, batch: &mut DBTransaction, update: &CacheUpdate, height: BlockNumber, hash: H256) -> Option<(u64, H256)> {
+        batch.put(db::COL_EXTRA, b"current_hash", &hash);
 
-        let subtask = chain.clone();
-        thread::spawn(move || {
-            info!("hanle pending!");
-            let dur = { 1000 / subtask.config.read().nps };
-            let dur = Duration::from_millis(dur);
-            loop {
-                thread::sleep(dur);
-                subtask.handle_pending();
+        let depth = { self.config.read().finality_depth };
+        if height > depth && height - depth > *self.finalized_height.read() {
+            let finalized_height = height - depth;
+            let pending = update.block_hashes.iter().rev().find(|b| b.0 == finalized_height).map(|b| b.1);
+            if let Some(finalized_hash) = pending.or_else(|| self.block_hash_by_number(finalized_height)) {
+                batch.put(db::COL_EXTRA, b"finalized_hash", &finalized_hash);
+                return Some((finalized_height, finalized_hash));
             }
-        });
-        chain
+        }
+        None
     }
 
-    fn save_status(&self, batch: &mut DBTransaction, height: BlockNumber, hash: H256) {
-        batch.put(db::COL_EXTRA, b"current_hash", &hash);
-        
+    fn set_status(&self, height: BlockNumber, hash: H256, finalized: Option<(u64, H256)>) {
         let mut current_height = self.current_height.write();
         let mut current_hash = self.current_hash.write();
         
         *current_height = height;
         *current_hash = hash;
 
+        if let Some((finalized_height, finalized_hash)) = finalized {
+            *self.finalized_height.write() = finalized_height;
+            *self.finalized_hash.write() = finalized_hash;
+        }
     }
 
-    fn insert_at(&self, block: Block, verified: bool) {
+    /// Get height and hash of the last final block.
+    pub fn finalized(&self) -> (u64, H256) {
+        let finalized_height = self.finalized_height.read();
+        let finalized_hash = self.finalized_hash.read();
+        (*finalized_height, *finalized_hash)
+    }
+
+    /// Whether the transaction with given hash is in a final block.
+    pub fn is_final(&self, hash: &H256) -> bool {
+        let (finalized_height, _) = self.finalized();
+        self.get_transaction_address(hash)
+            .and_then(|addr| self.get_block_header_by_hash(&addr.block_hash))
+            .map_or(false, |header| header.height <= finalized_height)
+    }
+
+    /// Whether the chain ending at given block keeps the last final block.
+    fn keeps_finalized(&self, height: u64, hash: H256) -> bool {
+        let (finalized_height, finalized_hash) = self.finalized();
+        self.block_hash_by_number_fork(finalized_height, height, hash) == Some(finalized_hash)
+    }
+
+    /// Write the block and move the best block to it if preferred, the
+    /// caches are updated once the batch is written.
+    fn insert_at(&self, block: Block, verified: bool, state: Option<State>, receipts: BlockReceipts) -> Result<(), Error> {
         let hash = block.hash();
         let height = block.height;
 
         let mut batch = self.db.transaction();
-        let rh = RichHeader {header: block.header, verified: verified};
-
-        { 
-            let mut write_headers = self.block_headers.write();
-            batch.write_with_cache(db::COL_HEADERS, &mut *write_headers, hash, rh.clone(), CacheUpdatePolicy::Overwrite);
-        }
-        {
-            let mut write_bodies = self.block_bodies.write();
-            batch.write_with_cache(db::COL_BODIES, &mut *write_bodies, hash, block.body, CacheUpdatePolicy::Overwrite);
+        if let Some(mut state) = state {
+            state.commit(&mut batch);
         }
+        let rh = RichHeader {header: block.header, verified: verified};
+        batch.write(db::COL_HEADERS, &hash, &rh);
+        batch.write(db::COL_BODIES, &hash, &block.body);
+        batch.write(db::COL_EXTRA, &hash, &receipts);
+        // switching the best block reads the transactions of the new block.
+        self.block_bodies.write().insert(hash, block.body);
 
-        let mut rng = thread_rng();
+        let mut update = CacheUpdate::default();
+        update.headers.push(rh.clone());
 
         let current_height = { *self.current_height.read() };
         let current_hash = { *self.current_hash.read() };
 
+        let mut status = None;
         if height == current_height + 1 
-           || (height == current_height && (rng.gen_range(0, 1) == 0 || current_hash == H256::default())) {
+           || (height == current_height && (current_hash == H256::default() || self.is_preferred(&rh, &current_hash))) {
            
-            if self.adjust_block_hashes(&mut batch, rh) {
-                self.save_status(&mut batch, height, hash);
+            // the new header is not written yet, follow its parent.
+            if height > 0 && !self.keeps_finalized(height - 1, rh.parent_hash) {
+                info!("Refuse to revert finalized block {:?} {:?}", height, hash);
+            } else if self.adjust_block_hashes(&mut batch, &mut update, rh) {
+                status = Some(self.save_status(&mut batch, &update, height, hash));
             } else {
                 info!("Switch Long Fork Error {:?} {:?}", height, hash);
             }
         }
 
-        self.db.write(batch).expect("DB write failed.");
+        if let Err(e) = self.db.write(batch) {
+            self.block_bodies.write().remove(&hash);
+            self.pending_events.lock().clear();
+            return Err(Error::Database(e));
+        }
+        self.block_receipts.write().insert(hash, receipts);
+        self.apply_cache_update(update);
+        if let Some(finalized) = status {
+            self.set_status(height, hash, finalized);
+            self.print_chain(height);
+        }
 
+        self.events.notify(ChainEvent::BlockImported { hash: hash, height: height });
+        let events: Vec<ChainEvent> = self.pending_events.lock().drain(..).collect();
+        for event in events {
+            self.events.notify(event);
+        }
+        if *self.current_hash.read() == hash {
+            self.events.notify(ChainEvent::NewHead { hash: hash, height: height });
+        }
+        Ok(())
+    }
+
+    fn apply_cache_update(&self, update: CacheUpdate) {
+        {
+            let mut block_headers = self.block_headers.write();
+            for header in update.headers {
+                block_headers.insert(header.hash(), header);
+            }
+        }
+        {
+            let mut block_hashes = self.block_hashes.write();
+            for &(height, hash) in &update.block_hashes {
+                block_hashes.insert(height, hash);
+            }
+        }
+        {
+            let mut transaction_addresses = self.transaction_addresses.write();
+            for h in &update.removed_transactions {
+                transaction_addresses.remove(h);
+            }
+            for &(h, ref addr) in &update.transaction_addresses {
+                transaction_addresses.insert(h, addr.clone());
+            }
+        }
+        {
+            let mut cache_man = self.cache_man.lock();
+            for &(height, _) in &update.block_hashes {
+                cache_man.note_used(CacheId::BlockHashes(height));
+            }
+            for &(h, _) in &update.transaction_addresses {
+                cache_man.note_used(CacheId::TransactionAddresses(h));
+            }
+        }
+        if let Some(txs_cache) = update.txs_cache {
+            *self.txs_cache.write() = txs_cache;
+        }
+    }
+
+    /// Subscribe to chain events.
+    pub fn subscribe(&self) -> Receiver<ChainEvent> {
+        self.events.subscribe()
+    }
+
+    /// Reorg event if making `blocks` canonical replaces other blocks.
+    fn reorg_event(&self, blocks: &[BlockInfo]) -> Option<ChainEvent> {
+        let mut blocks: Vec<&BlockInfo> = blocks.iter().collect();
+        blocks.sort_by_key(|b| b.height);
+        blocks.dedup_by_key(|b| b.hash);
+
+        let retracted: Vec<H256> = blocks.iter().filter_map(|b| match self.block_hash_by_number(b.height) {
+            Some(h) if h != b.hash => Some(h),
+            _ => None,
+        }).collect();
+        if retracted.is_empty() {
+            return None;
+        }
+        let enacted = blocks.iter().map(|b| b.hash).collect();
+        Some(ChainEvent::Reorg { retracted: retracted, enacted: enacted })
+    }
+
+    /// Whether a block should replace the best block at the same height.
+    fn is_preferred(&self, header: &RichHeader, current_hash: &H256) -> bool {
+        match self.get_block_header_by_hash(current_hash) {
+            Some(current) => self.fork_choice.prefer(&header.header, &current.header),
+            None => true,
+        }
     }
 
     pub fn insert(&self, block: Block) -> Result<(), Error> {
+        self.insert_from(block, None)
+    }
+
+    /// Insert a block received from given peer.
+    ///
+    /// Blocks with unknown parent or future timestamp are buffered, and
+    /// count against the peer's share of the buffers.
+    pub fn insert_from(&self, block: Block, origin: Option<PeerId>) -> Result<(), Error> {
+        let hash = block.hash();
+        let ret = self.import_from(block, origin);
+        match ret {
+            // buffered, imported or rejected later.
+            Err(Error::UnknownParent) | Err(Error::FutureBlock) | Ok(_) => {}
+            Err(ref e) => self.events.notify(ChainEvent::BlockRejected { hash: hash, error: e.clone() }),
+        }
+        ret
+    }
+
+    fn import_from(&self, block: Block, origin: Option<PeerId>) -> Result<(), Error> {
+        match self.block_basic_check(&block) {
+            Err(Error::UnknownParent) => {
+                self.unknown_parent.write().push(block, origin);
+                return Err(Error::UnknownParent);
+            }
+            Err(Error::FutureBlock) => {
+                self.future_blocks.write().push(block, origin);
+                return Err(Error::FutureBlock);
+            }
+            ret => ret?,
+        }
+
+        self.check_equivocation(&block.header);
+        self.insert_checked(block)
+    }
+
+    /// Index the header by signer and timestamp, and keep the evidence if
+    /// the signer already signed another header for this timestamp.
+    fn check_equivocation(&self, header: &Header) {
+        let signer = match header.sign_public() {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        let slot = SignerSlot { signer: signer, timestamp: header.timestamp };
+        let hash = header.hash();
+
+        // the lock also orders concurrent imports for the same slot.
+        let mut equivocations = self.equivocations.write();
+        let mut batch = self.db.transaction();
+        let first: Option<H256> = self.db.read(db::COL_EXTRA, &slot);
+        match first {
+            None => batch.write(db::COL_EXTRA, &slot, &hash),
+            Some(first) if first == hash => return,
+            Some(first) => {
+                let first = match self.get_block_header_by_hash(&first) {
+                    Some(h) => h.header,
+                    None => return,
+                };
+                if equivocations.iter().any(|e| e.signer == signer && e.hashes() == (first.hash(), hash)) {
+                    return;
+                }
+                let evidence = Equivocation::new(signer, first, header.clone());
+                warn!("equivocation by {:?} at {}: {:?}", signer, header.timestamp, evidence);
+                equivocations.push(evidence);
+                batch.put(db::COL_EXTRA, b"equivocations", &rlp::encode_list::<Equivocation, _>(&equivocations[..]));
+            }
+        }
+        self.db.write(batch).expect("DB write failed.");
+    }
+
+    /// Get the equivocations seen so far, oldest first.
+    pub fn equivocations(&self) -> Vec<Equivocation> {
+        self.equivocations.read().clone()
+    }
+
+    /// Check equivocation evidence against the best block's state.
+    ///
+    /// Fails once the offender is removed or its removal is queued.
+    pub fn check_evidence(&self, evidence: &Equivocation) -> Result<(), Error> {
+        State::from_existing(self.db.clone(), self.state_root())?.check_evidence(evidence)
+    }
+
+    /// Insert a block from a trusted source, such as our own export.
+    ///
+    /// Proofs, signatures and timestamps against the clock are not checked,
+    /// transactions and state still are.
+    pub fn insert_trusted(&self, block: Block) -> Result<(), Error> {
+        block.check_transactions_root()?;
+
+        if self.get_block_header_by_hash(&block.hash()) != None {
+            return Err(Error::DuplicateBlock);
+        }
+
+        let parent = self.get_block_header_by_hash(&block.parent_hash).ok_or(Error::UnknownParent)?;
+        if block.height != parent.height + 1 || block.timestamp <= parent.timestamp {
+            return Err(Error::InvalidTimestamp);
+        }
+
+        self.insert_checked(block)
+    }
+
+    fn insert_checked(&self, block: Block) -> Result<(), Error> {
         let hash = block.hash();
 
-        self.block_basic_check(&block)?;
+        let _lock = self.insert_lock.lock();
+        if self.get_block_header_by_hash(&hash).is_some() {
+            return Err(Error::DuplicateBlock);
+        }
+        if !self.keeps_finalized(block.height - 1, block.parent_hash) {
+            return Err(Error::RevertFinalized);
+        }
         
         let checked = self.check_transactions(&block)?;
+
+        let (state, receipts) = self.check_state(&block)?;
         
-        self.insert_at(block, checked);
+        self.insert_at(block, checked, Some(state), receipts)?;
 
         self.sender.lock().send(hash).unwrap();
 
         Ok(())
     }
 
+    /// Get the consensus engine.
+    pub fn consensus(&self) -> &Consensus {
+        &*self.consensus
+    }
+
+    /// Get the clock blocks are checked against.
+    pub fn clock(&self) -> Arc<Clock> {
+        self.clock.clone()
+    }
+
     pub fn anc_height(&self, height: u64) -> u64 {
-        let len = {self.config.read().epoch_len};
-        let mut a = height / len;
-        if a > 0 { a -= 1}
-        a * len
+        self.consensus.anc_height(height)
     }
 
     pub fn anc_hash(&self, height: u64, hash: H256) -> Option<H256> {
         let anc_height = self.anc_height(height + 1);
         match self.block_hash_by_number_fork(anc_height, height, hash) {
             None => None,
-            Some(h) => self.get_block_header_by_hash(&h).map(|header|header.proof.time_signature.sha3())
+            Some(h) => self.get_block_header_by_hash(&h).map(|header| self.consensus.seed(&header))
         }
     }
 
@@ -355,13 +709,15 @@ impl Chain {
     pub fn block_basic_check(&self, block: &Block) -> Result<(), Error> {
         let hash = block.hash();
 
-        if block.difficulty() > self.config.read().get_difficulty() {
+        block.check_transactions_root()?;
+
+        if !self.consensus.is_eligible(&block.proof.time_signature) {
             return Err(Error::InvalidProof);
         }
 
         let config = self.config.read();
 
-        let now = match config.ntp_now() {
+        let now = match self.clock.now() {
             Some(t) => t,
             _ => return Err(Error::NTPError),
         };
@@ -383,30 +739,59 @@ impl Chain {
                 }          
             }
             None => {
-                let mut unknown_parent = self.unknown_parent.write();
-                let blocks = unknown_parent.entry(block.parent_hash).or_insert_with(|| Vec::new());
-                blocks.push(block.clone());
                 return Err(Error::UnknownParent);
             }
         }
 
         if block.timestamp > now {
-            self.future_blocks.write().push(block.clone());
             return Err(Error::FutureBlock);
         }
         
         let height = block.height;
         let anc_hash = self.anc_hash(height - 1, block.parent_hash).ok_or(Error::UnknownAncestor)?;
-        let sign_pub = block.sign_public()?;
-        let (proof_pub, proof_g) = config.get_proof_pub(&sign_pub).ok_or(Error::InvalidPublicKey)?;
+        let validators = self.validators_at(&block.parent_hash).unwrap_or_else(|| ValidatorSet::from(config.get_keygroups().clone()));
+        self.check_header_proof(&block.header, anc_hash, &validators)
+    }
 
-        if !block.verify_proof(anc_hash, proof_pub, proof_g) {
+    /// Check the signer of the header is a validator holding a winning proof for `seed`.
+    pub fn check_header_proof(&self, header: &Header, seed: H256, validators: &ValidatorSet) -> Result<(), Error> {
+        if !self.consensus.is_eligible(&header.proof.time_signature) {
+            return Err(Error::InvalidProof);
+        }
+
+        let sign_pub = header.sign_public()?;
+        let (proof_pub, proof_g) = validators.proof_keys(&sign_pub).ok_or(Error::InvalidPublicKey)?;
+
+        if !self.consensus.verify_proof(header, seed, proof_pub, proof_g) {
             return Err(Error::InvalidProofKey);
         }
 
         Ok(())
     }
 
+    /// Check the proof of a header whose parent may not be imported yet.
+    ///
+    /// Keys are taken from the validator set of `epoch_hash`, the block at
+    /// `validators_height(header.height)` on the header's branch, which must
+    /// be imported.
+    pub fn verify_header(&self, header: &Header, seed: H256, epoch_hash: &H256) -> Result<(), Error> {
+        if self.get_block_header_by_hash(epoch_hash).is_none() {
+            return Err(Error::UnknownAncestor);
+        }
+        let validators = match self.validators_at(epoch_hash) {
+            Some(v) => v,
+            None => ValidatorSet::from(self.config.read().get_keygroups().clone()),
+        };
+        self.check_header_proof(header, seed, &validators)
+    }
+
+    /// Height of the block whose state holds the validator set of blocks at `height`.
+    ///
+    /// See `validator::validators_height`.
+    pub fn validators_height(&self, height: u64) -> u64 {
+        validator::validators_height(height, self.config.read().epoch_len)
+    }
+
     pub fn transactions_diff(&self, mut height: u64, mut hash: H256) -> Result<(u64, HashSet<H256>), Error> {
         let mut txs_set = HashSet::new();
         let mut bs = {self.config.read().buffer_size};
@@ -453,7 +838,7 @@ impl Chain {
 
         let bi = txs_cache.get(i as usize).unwrap();
         
-        (bi.height, bi.timestamp * 1000 / nps)
+        (bi.height, tx_left_bound(bi.timestamp, nps))
 
     }
 
@@ -470,6 +855,50 @@ impl Chain {
         Ok(true)
     }
 
+    /// Apply the block's transactions on its parent's state and check the resulting state and receipts roots.
+    pub fn check_state(&self, block: &Block) -> Result<(State, BlockReceipts), Error> {
+        let parent = self.get_block_header_by_hash(&block.parent_hash).ok_or(Error::UnknownParent)?;
+        let (state, receipts) = self.execute(&parent, &block.body.transactions)?;
+
+        if state.root() != block.state_root {
+            return Err(Error::InvalidStateRoot);
+        }
+
+        if receipts.receipts_root() != block.receipts_root {
+            return Err(Error::InvalidReceiptsRoot);
+        }
+
+        Ok((state, receipts))
+    }
+
+    /// Apply transactions on top of the parent's state.
+    ///
+    /// The last block of an epoch also makes the pending validator changes
+    /// active, so they apply from the first block of the next epoch.
+    fn execute(&self, parent: &Header, txs: &[SignedTransaction]) -> Result<(State, BlockReceipts), Error> {
+        let mut state = State::from_existing(self.db.clone(), parent.state_root)?;
+        let receipts = state.apply_transactions(txs);
+        let epoch_len = { self.config.read().epoch_len };
+        if epoch_len > 0 && (parent.height + 2) % epoch_len == 0 {
+            state.promote_validators()?;
+        }
+        Ok((state, receipts))
+    }
+
+    /// Get the validator set active for the children of given block.
+    ///
+    /// `None` if the block's state has no validator set, e.g. a chain created without keygroups.
+    pub fn validators_at(&self, hash: &H256) -> Option<ValidatorSet> {
+        let header = self.get_block_header_by_hash(hash)?;
+        State::from_existing(self.db.clone(), header.state_root).ok()?.validators()
+    }
+
+    /// State trie nodes proving the validator set of given block, for light clients.
+    pub fn get_validators_proof(&self, hash: &H256) -> Option<Vec<Bytes>> {
+        let header = self.get_block_header_by_hash(hash)?;
+        Some(State::from_existing(self.db.clone(), header.state_root).ok()?.validators_proof())
+    }
+
     pub fn filter_transactions(&self, height: u64, hash: H256, txs: Vec<SignedTransaction>) -> Vec<SignedTransaction> {
         let (height, mut txs_set) = self.transactions_diff(height, hash).unwrap();
         let txs_cache = self.txs_cache.read();
@@ -498,19 +927,29 @@ impl Chain {
 
     pub fn gen_block(&self, height: u64, hash: H256, time: u64, time_sig: Vec<u8>, txs: Vec<SignedTransaction>) -> Block {
         
+        let _lock = self.insert_lock.lock();
         let txs = self.filter_transactions(height, hash, txs);
 
         let signer_private_key = {self.config.read().get_signer_private_key()};
 
         let mut block = Block::init(height + 1, time, hash, txs, time_sig);
+
+        let parent = self.get_block_header_by_hash(&hash).expect("parent not found");
+        let (state, receipts) = self.execute(&parent, &block.body.transactions).expect("parent state not found");
+        block.state_root = state.root();
+        block.receipts_root = receipts.receipts_root();
         
         block.sign(&signer_private_key);
 
-        self.insert_at(block.clone(), true);
+        self.insert_at(block.clone(), true, Some(state), receipts).expect("DB write failed.");
 
         block
     }
 
+    pub fn genesis_hash(&self) -> H256 {
+        self.block_hash_by_number(0).expect("genesis not found!")
+    }
+
     pub fn get_status(&self) -> (u64, H256) {
         let current_height = self.current_height.read();
         let current_hash = self.current_hash.read();
@@ -544,6 +983,20 @@ impl Chain {
         *self.current_height.read()
     }
 
+    /// Get the state root of the best block.
+    ///
+    /// State is addressed by root and never pruned, so switching to a fork
+    /// rolls back to the fork point's root and replays the fork on top of it.
+    pub fn state_root(&self) -> H256 {
+        let hash = { *self.current_hash.read() };
+        self.get_block_header_by_hash(&hash).expect("header not found!").state_root
+    }
+
+    /// Get the account with given address in the best block's state.
+    pub fn get_account(&self, address: &Address) -> Option<Account> {
+        State::from_existing(self.db.clone(), self.state_root()).ok().and_then(|state| state.account(address))
+    }
+
     pub fn block_hash_by_number(&self, number: u64) -> Option<H256> {
         {
             let txs_cache = self.txs_cache.read();
@@ -576,6 +1029,26 @@ impl Chain {
         self.block_hash_by_number(height).map_or(None, |h| self.get_block_body_by_hash(&h))
     }
 
+    /// Get up to `count` canonical headers starting at `from`.
+    pub fn get_headers(&self, from: u64, count: u64) -> Vec<Header> {
+        let best = self.current_height();
+        let mut headers = Vec::new();
+        let mut height = from;
+        while height <= best && (headers.len() as u64) < count {
+            match self.block_hash_by_number(height).and_then(|h| self.get_block_header_by_hash(&h)) {
+                Some(rh) => headers.push(rh.header),
+                None => break,
+            }
+            height += 1;
+        }
+        headers
+    }
+
+    /// Get the bodies of the known blocks among `hashes`.
+    pub fn get_bodies(&self, hashes: &[H256]) -> Vec<(H256, Body)> {
+        hashes.iter().filter_map(|h| self.get_block_body_by_hash(h).map(|b| (*h, b))).collect()
+    }
+
     pub fn get_block_by_hash(&self, hash: &H256) -> Option<Block> {
         if let (Some(h), Some(b)) = (self.get_block_header_by_hash(hash), self.get_block_body_by_hash(hash)) {
             Some( Block {
@@ -594,6 +1067,30 @@ impl Chain {
         result
     }
 
+    /// Get the canonical header, index and merkle branch proving inclusion of the transaction with given hash.
+    pub fn get_transaction_proof(&self, hash: &H256) -> Option<(Header, usize, Vec<ProofNode>)> {
+        let addr = self.get_transaction_address(hash)?;
+        let header = self.get_block_header_by_hash(&addr.block_hash)?;
+        if self.block_hash_by_number(header.height) != Some(addr.block_hash) {
+            return None;
+        }
+        let body = self.get_block_body_by_hash(&addr.block_hash)?;
+        body.transaction_proof(addr.index).map(|proof| (header.header, addr.index, proof))
+    }
+
+    /// Get receipts of block with given hash.
+    pub fn get_block_receipts(&self, hash: &H256) -> Option<BlockReceipts> {
+        let result = self.db.read_with_cache(db::COL_EXTRA, &self.block_receipts, hash);
+        self.cache_man.lock().note_used(CacheId::BlockReceipts(hash.clone()));
+        result
+    }
+
+    /// Get the receipt of transaction with given hash.
+    pub fn get_receipt(&self, hash: &H256) -> Option<Receipt> {
+        let addr = self.get_transaction_address(hash)?;
+        self.get_block_receipts(&addr.block_hash).and_then(|r| r.receipts.get(addr.index).cloned())
+    }
+
     fn get_unverif_headers(&self, mut header: RichHeader) -> Vec<RichHeader> {
         let mut headers: Vec<RichHeader> = Vec::new();
         loop {
@@ -668,7 +1165,7 @@ impl Chain {
         Ok(())
     }
     
-    pub fn switch_long_fork(&self, batch: &mut DBTransaction, header: RichHeader) -> Result<(), Error> {
+    fn switch_long_fork(&self, batch: &mut DBTransaction, update: &mut CacheUpdate, header: RichHeader) -> Result<(), Error> {
         let mut txs_cache = {self.txs_cache.read().clone()};
         let current_height = {*self.current_height.read()};
         // let best = header.height;
@@ -682,8 +1179,15 @@ impl Chain {
         }
         
         let to = txs_cache.total();
-        //get fork headers
-        let mut fork_blocks = self.get_fork_chain(header.height, header.hash());
+        //get fork headers, the new header is not written yet
+        let mut fork_blocks = vec![BlockInfo {
+            hash: header.hash(),
+            height: header.height,
+            timestamp: header.timestamp,
+            transactions: self.block_transaction_hashes_by_hash(&header.hash()),
+        }];
+        fork_blocks.extend(self.get_fork_chain(header.height - 1, header.parent_hash));
+        let reorg = self.reorg_event(&fork_blocks);
         
         //get the last header
         let hash = match n < to {
@@ -709,11 +1213,9 @@ impl Chain {
             self.transactions_check(&txs, HashSet::new(), &txs_cache, header.height, header.height)?;
 
             //mark header as verified
-            {   
-                header.verified = true;
-                let mut write_headers = self.block_headers.write();
-                batch.write_with_cache(db::COL_HEADERS, &mut *write_headers, header.hash(), header.clone(), CacheUpdatePolicy::Overwrite);
-            }
+            header.verified = true;
+            batch.write(db::COL_HEADERS, &header.hash(), &header);
+            update.headers.push(header.clone());
 
             let b = BlockInfo{hash: header.hash(), height: header.height, timestamp: header.timestamp, transactions: tx_hashes};
             txs_cache.pop_front();
@@ -722,17 +1224,14 @@ impl Chain {
 
         }
 
-        self.update_transaction_addresses(batch, fork_blocks.clone());
-        self.update_block_number(batch, fork_blocks);
+        self.update_transaction_addresses(batch, update, fork_blocks.clone());
+        self.update_block_number(batch, update, fork_blocks);
+        update.txs_cache = Some(txs_cache);
 
-        //update chain's txs_cache
-        {
-            let mut txs_cache_write = self.txs_cache.write();
-            *txs_cache_write = txs_cache;
+        if let Some(event) = reorg {
+            self.pending_events.lock().push(event);
         }
 
-        self.print_chain(header.height);
-
         Ok(())
     }
 
@@ -761,15 +1260,14 @@ impl Chain {
                                          .iter().map(|t| t.hash()).collect()
     }
 
-    pub fn update_block_number(&self, batch: &mut DBTransaction, blocks: Vec<BlockInfo>) {
-        let mut block_hashes = self.block_hashes.write();
+    fn update_block_number(&self, batch: &mut DBTransaction, update: &mut CacheUpdate, blocks: Vec<BlockInfo>) {
         for b in blocks {
-            batch.write_with_cache(db::COL_EXTRA, &mut *block_hashes, b.height, b.hash, CacheUpdatePolicy::Overwrite);
-            self.cache_man.lock().note_used(CacheId::BlockHashes(b.height));
+            batch.write(db::COL_EXTRA, &b.height, &b.hash);
+            update.block_hashes.push((b.height, b.hash));
         }
     }
 
-    pub fn update_transaction_addresses(&self, batch: &mut DBTransaction, blocks: Vec<BlockInfo>) {
+    fn update_transaction_addresses(&self, batch: &mut DBTransaction, update: &mut CacheUpdate, blocks: Vec<BlockInfo>) {
         let mut old_txs = Vec::new();
         let mut new_txs = Vec::new();
 
@@ -788,25 +1286,24 @@ impl Chain {
 
         }
 
-        let mut transaction_addresses = self.transaction_addresses.write();
-
         for h in old_txs {
-            batch.delete_with_cache(db::COL_EXTRA, &mut *transaction_addresses, h);
+            Writable::delete::<TransactionAddress, _>(batch, db::COL_EXTRA, &h);
+            update.removed_transactions.push(h);
         }
 
         for (h, addr) in new_txs {
-            batch.write_with_cache(db::COL_EXTRA, &mut *transaction_addresses, h, addr, CacheUpdatePolicy::Overwrite);
-            self.cache_man.lock().note_used(CacheId::TransactionAddresses(h)); 
+            batch.write(db::COL_EXTRA, &h, &addr);
+            update.transaction_addresses.push((h, addr));
         }
 
     }
 
     //TODO: get old hash from queue
-    pub fn adjust_block_hashes(&self, batch: &mut DBTransaction, header: RichHeader) -> bool {
+    fn adjust_block_hashes(&self, batch: &mut DBTransaction, update: &mut CacheUpdate, header: RichHeader) -> bool {
         info!("begin adjust best blocks {:?} {:?}", header.height, header.hash());
 
         if !header.verified {
-            return self.switch_long_fork(batch, header).is_ok();
+            return self.switch_long_fork(batch, update, header).is_ok();
         }
 
         let mut fork_blocks = match header.height > 0 {
@@ -816,14 +1313,17 @@ impl Chain {
 
         let txs_hashes = self.block_transaction_hashes_by_hash(&header.hash());
         fork_blocks.push(BlockInfo{hash: header.hash(), height: header.height, timestamp: header.timestamp, transactions: txs_hashes.clone()});
+        if let Some(event) = self.reorg_event(&fork_blocks) {
+            self.pending_events.lock().push(event);
+        }
         
-        self.update_transaction_addresses(batch, fork_blocks.clone());
+        self.update_transaction_addresses(batch, update, fork_blocks.clone());
 
-        self.update_block_number(batch, fork_blocks.clone());
+        self.update_block_number(batch, update, fork_blocks.clone());
 
         {
             let current_height = { *self.current_height.read()}; 
-            let mut txs_cache = self.txs_cache.write();
+            let mut txs_cache = { self.txs_cache.read().clone() };
             let len = txs_cache.total();
             if current_height < header.height {
                 //left shift
@@ -831,9 +1331,9 @@ impl Chain {
                 txs_cache.queue.push_back(front);
             } 
             self.update_txs_cache(&mut txs_cache, fork_blocks, len);
+            update.txs_cache = Some(txs_cache);
         }
         
-        self.print_chain(header.height);
         true
     }
 
@@ -853,27 +1353,56 @@ impl Chain {
         info!("}}");
     }
 
-    fn handle_pending(&self) {
-        if let Some(now) = self.config.read().ntp_now() {
+    fn insert_children(&self, hash: H256) {
+        let blocks = self.unknown_parent.write().take(|b| b.parent_hash == hash);
+        for (b, origin) in blocks {
+            let _ = self.insert_from(b, origin);
+        }
+    }
 
-            let left: Vec<Block> = self.future_blocks.read().clone().into_iter().filter(|b| {
-                if b.timestamp <= now {
-                    self.insert(b.clone()).expect("insert block failed");
-                    false
-                } else {
-                    true
-                }
-            }).collect();
+    /// Insert the buffered blocks whose parent was imported since the last
+    /// call, for chains built with `init_driven`.
+    pub fn handle_orphans(&self) {
+        loop {
+            let hash = match self.imported.lock().try_recv() {
+                Ok(h) => h,
+                Err(_) => return,
+            };
+            self.insert_children(hash);
+        }
+    }
+
+    /// Drop expired orphans and insert the future blocks now due.
+    pub fn handle_pending(&self) {
+        self.unknown_parent.write().expire();
 
-            *self.future_blocks.write() = left;
+        let now = self.clock.now();
+        if let Some(now) = now {
+            let blocks = self.future_blocks.write().take(|b| b.timestamp <= now);
+            for (b, origin) in blocks {
+                if let Err(e) = self.insert_from(b, origin) {
+                    trace!("insert pending block failed {:?}", e);
+                }
+            }
         }
     }
 
+    /// Get the counters of the buffer of blocks with unknown parent.
+    pub fn orphan_stats(&self) -> BufferStats {
+        self.unknown_parent.read().stats()
+    }
+
+    /// Get the counters of the buffer of blocks with future timestamp.
+    pub fn future_stats(&self) -> BufferStats {
+        self.future_blocks.read().stats()
+    }
+
     /// Get current cache size.
     pub fn cache_size(&self) -> CacheSize {
         CacheSize {
             blocks: self.block_headers.read().heap_size_of_children() + self.block_bodies.read().heap_size_of_children(),
             transaction_addresses: self.transaction_addresses.read().heap_size_of_children(),
+            block_receipts: self.block_receipts.read().heap_size_of_children(),
         }
     }
 
@@ -885,6 +1414,7 @@ impl Chain {
         let mut block_bodies = self.block_bodies.write();
         let mut block_hashes = self.block_hashes.write();
         let mut transaction_addresses = self.transaction_addresses.write();
+        let mut block_receipts = self.block_receipts.write();
 
         let mut cache_man = self.cache_man.lock();
         cache_man.collect_garbage(current_size, | ids | {
@@ -894,6 +1424,7 @@ impl Chain {
                     CacheId::BlockBody(ref h) => { block_bodies.remove(h); },
                     CacheId::BlockHashes(ref h) => { block_hashes.remove(h); }
                     CacheId::TransactionAddresses(ref h) => { transaction_addresses.remove(h); }
+                    CacheId::BlockReceipts(ref h) => { block_receipts.remove(h); }
                 }
             }
 
@@ -901,12 +1432,677 @@ impl Chain {
             block_bodies.shrink_to_fit();
             block_hashes.shrink_to_fit();
             transaction_addresses.shrink_to_fit();
+            block_receipts.shrink_to_fit();
 
             block_headers.heap_size_of_children() +
             block_bodies.heap_size_of_children() +
             block_hashes.heap_size_of_children() +
-            transaction_addresses.heap_size_of_children()
+            transaction_addresses.heap_size_of_children() +
+            block_receipts.heap_size_of_children()
         });
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvdb::in_memory;
+    use util::config::{Config, ForkChoiceRule, BlockBufferConfig, RpcConfig, ClockSource, ByzantineConfig, KeyGroup};
+    use util::clock::MockClock;
+    use util::{SHA3_NULL_RLP, verify_merkle_proof};
+    use crypto::{sign, KeyPair};
+    use bls;
+    use verify;
+    use validator::{Validator, ValidatorChange};
+    use bincode::{serialize, deserialize, Infinite};
+
+    fn test_config(fork_choice: ForkChoiceRule) -> SleepyConfig {
+        let config = Config {
+            id_card: 0,
+            port: 40000,
+            max_peer: 2,
+            steps: 6,
+            nps: 10,
+            miner_private_key: Vec::new(),
+            signer_private_key: test_key(),
+            peers: Vec::new(),
+            keygroups: Vec::new(),
+            epoch_len: 10,
+            start_time: 0,
+            ntp_servers: Vec::new(),
+            buffer_size: 5,
+            fork_choice: fork_choice,
+            finality_depth: 2,
+            block_buffer: BlockBufferConfig::default(),
+            rpc: RpcConfig::default(),
+            clock: ClockSource::System,
+            byzantine: ByzantineConfig::default(),
+        };
+        SleepyConfig { config: config, public_keys: HashMap::new() }
+    }
+
+    fn test_chain_with(fork_choice: ForkChoiceRule) -> Arc<Chain> {
+        let db = in_memory(db::NUM_COLUMNS.unwrap());
+        Chain::init(Arc::new(RwLock::new(test_config(fork_choice))), Arc::new(db)).unwrap()
+    }
+
+    fn test_chain() -> Arc<Chain> {
+        test_chain_with(ForkChoiceRule::default())
+    }
+
+    fn test_chain_with_clock(clock: Arc<Clock>) -> Arc<Chain> {
+        let db = in_memory(db::NUM_COLUMNS.unwrap());
+        let config = Arc::new(RwLock::new(test_config(ForkChoiceRule::default())));
+        let consensus = Box::new(BlsLottery::new(config.clone()));
+        Chain::init_with(config, Arc::new(db), consensus, clock).unwrap()
+    }
+
+    /// Every proof wins, and it is valid when it is the signer's proof key.
+    struct KeyLottery;
+
+    impl Consensus for KeyLottery {
+        fn anc_height(&self, _height: u64) -> u64 {
+            0
+        }
+
+        fn seed(&self, _ancestor: &Header) -> H256 {
+            H256::default()
+        }
+
+        fn gen_proof(&self, _time: u64, _height: u64, _seed: H256) -> Vec<u8> {
+            Vec::new()
+        }
+
+        fn is_eligible(&self, _proof: &[u8]) -> bool {
+            true
+        }
+
+        fn verify_proof(&self, header: &Header, _seed: H256, public_key: Vec<u8>, _g: Vec<u8>) -> bool {
+            header.proof.time_signature == public_key
+        }
+    }
+
+    fn validator(key: &H256) -> KeyGroup {
+        KeyGroup {
+            proof_public_key: key.to_vec(),
+            proof_public_g: Vec::new(),
+            signer_public_key: *KeyPair::from_privkey(*key).unwrap().pubkey(),
+        }
+    }
+
+    /// Chain with given validators at genesis, checked with `KeyLottery`.
+    fn validator_chain(keys: &[H256]) -> Arc<Chain> {
+        let mut config = test_config(ForkChoiceRule::default());
+        config.keygroups = keys.iter().map(validator).collect();
+        let db = in_memory(db::NUM_COLUMNS.unwrap());
+        Chain::init_with_consensus(Arc::new(RwLock::new(config)), Arc::new(db), Box::new(KeyLottery)).unwrap()
+    }
+
+    /// Block on `parent` signed by `key`, with the winning `KeyLottery` proof.
+    fn signed_block(chain: &Chain, parent: H256, t: u64, key: &H256, txs: Vec<SignedTransaction>) -> Block {
+        let parent = chain.get_block_header_by_hash(&parent).unwrap();
+        let mut block = Block::init(parent.height + 1, t, parent.hash(), txs, key.to_vec());
+        let (state, receipts) = chain.execute(&parent, &block.body.transactions).unwrap();
+        block.state_root = state.root();
+        block.receipts_root = receipts.receipts_root();
+        block.sign(key);
+        block
+    }
+
+    fn import(chain: &Chain, block: &Block) {
+        let (state, receipts) = chain.check_state(block).unwrap();
+        chain.insert_at(block.clone(), true, Some(state), receipts).unwrap();
+    }
+
+    fn test_key() -> H256 {
+        H256::from("40f2d8f8e1594579824fd04edfc7ff1ddffd6be153b23f4318e1acff037d3ea9")
+    }
+
+    fn test_tx(t: u64) -> SignedTransaction {
+        let mut tx = SignedTransaction::new(t);
+        tx.set_data(vec![t as u8]);
+        tx.signature = sign(&test_key(), &tx.hash()).unwrap().into();
+        tx
+    }
+
+    #[test]
+    fn gen_block_commits_transactions_root() {
+        let chain = test_chain();
+        let (height, hash) = chain.get_status();
+        let block = chain.gen_block(height, hash, 1, Vec::new(), vec![test_tx(1), test_tx(2)]);
+
+        assert_eq!(block.body.transactions.len(), 2);
+        assert_eq!(block.transactions_root, block.body.transactions_root());
+        assert!(block.transactions_root != SHA3_NULL_RLP);
+    }
+
+    #[test]
+    fn insert_rejects_mismatched_transactions_root() {
+        let chain = test_chain();
+        let (height, hash) = chain.get_status();
+        let mut block = chain.gen_block(height, hash, 1, Vec::new(), vec![test_tx(1)]);
+        block.body.transactions = vec![test_tx(2)];
+
+        assert_eq!(chain.insert(block), Err(Error::InvalidTransactionsRoot));
+    }
+
+    #[test]
+    fn insert_rejects_stale_transaction_hash() {
+        let chain = test_chain();
+        let (height, hash) = chain.get_status();
+        // the root is built from the stale hash, so it still matches.
+        let mut tx = test_tx(1);
+        tx.transaction.data = vec![9];
+        let block = Block::init(height + 1, 1, hash, vec![tx], Vec::new());
+
+        assert_eq!(chain.insert(block), Err(Error::InvalidTransactionHash));
+    }
+
+    #[test]
+    fn transaction_proof_matches_header() {
+        let chain = test_chain();
+        let (height, hash) = chain.get_status();
+        let txs = vec![test_tx(1), test_tx(2), test_tx(3)];
+        chain.gen_block(height, hash, 1, Vec::new(), txs.clone());
+
+        let (header, index, proof) = chain.get_transaction_proof(&txs[2].hash()).unwrap();
+        assert_eq!(header.height, height + 1);
+        assert_eq!(index, 2);
+        assert!(verify_merkle_proof(&header.transactions_root, &txs[2].hash(), &proof));
+        assert_eq!(chain.get_transaction_proof(&test_tx(4).hash()), None);
+    }
+
+    #[test]
+    fn gen_block_commits_state_root() {
+        let chain = test_chain();
+        let (height, hash) = chain.get_status();
+        assert_eq!(chain.state_root(), SHA3_NULL_RLP);
+
+        let block = chain.gen_block(height, hash, 1, Vec::new(), vec![test_tx(1), test_tx(2)]);
+        assert!(block.state_root != SHA3_NULL_RLP);
+        assert_eq!(chain.state_root(), block.state_root);
+
+        let address = KeyPair::from_privkey(test_key()).unwrap().address();
+        assert_eq!(chain.get_account(&address).unwrap().nonce, 2);
+    }
+
+    #[test]
+    fn check_state_rejects_wrong_root() {
+        let chain = test_chain();
+        let (height, hash) = chain.get_status();
+        let mut block = Block::init(height + 1, 1, hash, vec![test_tx(1)], Vec::new());
+        assert_eq!(chain.check_state(&block).err(), Some(Error::InvalidStateRoot));
+
+        let mut state = State::from_existing(chain.db.clone(), SHA3_NULL_RLP).unwrap();
+        let receipts = state.apply_transactions(&block.body.transactions);
+        block.state_root = state.root();
+        assert_eq!(chain.check_state(&block).err(), Some(Error::InvalidReceiptsRoot));
+
+        block.receipts_root = receipts.receipts_root();
+        assert!(chain.check_state(&block).is_ok());
+    }
+
+    #[test]
+    fn receipts_are_recorded() {
+        let chain = test_chain();
+        let (height, hash) = chain.get_status();
+        let unsigned = SignedTransaction::new(3);
+        let block = chain.gen_block(height, hash, 1, Vec::new(), vec![test_tx(1), unsigned.clone()]);
+
+        let receipts = chain.get_block_receipts(&block.hash()).unwrap();
+        assert_eq!(block.receipts_root, receipts.receipts_root());
+
+        let receipt = chain.get_receipt(&test_tx(1).hash()).unwrap();
+        assert!(receipt.success);
+        assert_eq!(receipt.nonce, 1);
+        assert!(!chain.get_receipt(&unsigned.hash()).unwrap().success);
+    }
+
+    #[test]
+    fn forks_converge_whatever_the_order() {
+        for rule in vec![ForkChoiceRule::LowestProof, ForkChoiceRule::EarliestTimestamp] {
+            let miner = test_chain_with(rule);
+            let (_, genesis) = miner.get_status();
+            let a = miner.gen_block(0, genesis, 1, vec![1], Vec::new());
+            let b = miner.gen_block(0, genesis, 2, vec![2], Vec::new());
+            let c = miner.gen_block(0, genesis, 3, vec![3], Vec::new());
+            let x = miner.gen_block(1, a.hash(), 4, vec![4], Vec::new());
+            let y = miner.gen_block(1, c.hash(), 5, vec![5], Vec::new());
+
+            let expected = match fork_choice::from_rule(rule).prefer(&x.header, &y.header) {
+                true => x.hash(),
+                false => y.hash(),
+            };
+
+            let orders = vec![
+                vec![&a, &b, &c, &x, &y],
+                vec![&c, &b, &a, &y, &x],
+                vec![&c, &y, &a, &x, &b],
+                vec![&a, &x, &b, &c, &y],
+            ];
+
+            assert_eq!(miner.get_status(), (2, expected));
+            for order in orders {
+                let node = test_chain_with(rule);
+                for block in order {
+                    import(&node, block);
+                }
+                assert_eq!(node.get_status(), (2, expected));
+            }
+        }
+    }
+
+    #[test]
+    fn serves_headers_and_bodies() {
+        let chain = test_chain();
+        let (_, genesis) = chain.get_status();
+        let a = chain.gen_block(0, genesis, 1, vec![1], vec![test_tx(1)]);
+        let b = chain.gen_block(1, a.hash(), 2, vec![2], Vec::new());
+
+        let headers = chain.get_headers(1, 5);
+        assert_eq!(headers.iter().map(|h| h.hash()).collect::<Vec<_>>(), vec![a.hash(), b.hash()]);
+        assert_eq!(chain.get_headers(0, 1)[0].hash(), genesis);
+        assert!(chain.get_headers(3, 5).is_empty());
+
+        let bodies = chain.get_bodies(&[a.hash(), H256::from(1)]);
+        assert_eq!(bodies, vec![(a.hash(), a.body.clone())]);
+    }
+
+    #[test]
+    fn export_then_import() {
+        use export::{export_blocks, import_blocks};
+
+        let chain = test_chain();
+        let (_, genesis) = chain.get_status();
+        let a = chain.gen_block(0, genesis, 1, vec![1], vec![test_tx(1)]);
+        let b = chain.gen_block(1, a.hash(), 2, vec![2], vec![test_tx(2)]);
+
+        let mut file = Vec::new();
+        assert_eq!(export_blocks(&chain, 0, 10, &mut file).unwrap(), 3);
+
+        let other = test_chain();
+        assert_eq!(import_blocks(&other, &mut &file[..], false).unwrap(), 2);
+        assert_eq!(other.get_status(), (2, b.hash()));
+        assert_eq!(other.state_root(), chain.state_root());
+        assert_eq!(import_blocks(&other, &mut &file[..], false).unwrap(), 0);
+    }
+
+    #[test]
+    fn future_blocks_wait_for_the_clock() {
+        let clock = Arc::new(MockClock::new(4));
+        let chain = test_chain_with_clock(clock.clone());
+        let (_, genesis) = chain.get_status();
+        let block = Block::init(1, 5, genesis, Vec::new(), vec![1]);
+        chain.future_blocks.write().push(block, None);
+
+        chain.handle_pending();
+        assert_eq!(chain.future_stats().blocks, 1);
+        clock.fail();
+        chain.handle_pending();
+        assert_eq!(chain.future_stats().blocks, 1);
+        clock.set(5);
+        chain.handle_pending();
+        assert_eq!(chain.future_stats().blocks, 0);
+    }
+
+    #[test]
+    fn equivocation_is_recorded() {
+        let chain = test_chain();
+        let (_, genesis) = chain.get_status();
+        let a = chain.gen_block(0, genesis, 1, vec![1], Vec::new());
+        let b = chain.gen_block(0, genesis, 1, vec![2], Vec::new());
+        let c = chain.gen_block(1, a.hash(), 2, vec![3], Vec::new());
+
+        for header in vec![&a.header, &c.header, &a.header] {
+            chain.check_equivocation(header);
+        }
+        assert!(chain.equivocations().is_empty());
+
+        chain.check_equivocation(&b.header);
+        chain.check_equivocation(&b.header);
+        let evidence = chain.equivocations();
+        assert_eq!(evidence.len(), 1);
+        assert_eq!(evidence[0].hashes(), (a.hash(), b.hash()));
+        assert!(evidence[0].is_valid());
+    }
+
+    #[test]
+    fn reorg_is_reported() {
+        let miner = test_chain_with(ForkChoiceRule::EarliestTimestamp);
+        let (_, genesis) = miner.get_status();
+        let a = miner.gen_block(0, genesis, 1, vec![1], Vec::new());
+        let c = miner.gen_block(0, genesis, 3, vec![3], Vec::new());
+        let x = miner.gen_block(1, a.hash(), 4, vec![4], Vec::new());
+        let y = miner.gen_block(1, c.hash(), 5, vec![5], Vec::new());
+
+        let node = test_chain_with(ForkChoiceRule::EarliestTimestamp);
+        let events = node.subscribe();
+        let other = node.subscribe();
+        for block in vec![&c, &y, &a] {
+            import(&node, block);
+        }
+        let _: Vec<ChainEvent> = events.try_iter().collect();
+        import(&node, &x);
+
+        let expected = vec![
+            ChainEvent::BlockImported { hash: x.hash(), height: 2 },
+            ChainEvent::Reorg { retracted: vec![c.hash(), y.hash()], enacted: vec![a.hash(), x.hash()] },
+            ChainEvent::NewHead { hash: x.hash(), height: 2 },
+        ];
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), expected);
+
+        let mut all = vec![
+            ChainEvent::BlockImported { hash: c.hash(), height: 1 },
+            ChainEvent::NewHead { hash: c.hash(), height: 1 },
+            ChainEvent::BlockImported { hash: y.hash(), height: 2 },
+            ChainEvent::NewHead { hash: y.hash(), height: 2 },
+            ChainEvent::BlockImported { hash: a.hash(), height: 1 },
+        ];
+        all.extend(expected);
+        assert_eq!(other.try_iter().collect::<Vec<_>>(), all);
+    }
+
+    #[test]
+    fn buffered_blocks_are_not_rejected() {
+        let mut config = test_config(ForkChoiceRule::default());
+        config.keygroups = vec![validator(&test_key())];
+        let db = in_memory(db::NUM_COLUMNS.unwrap());
+        let clock = Arc::new(MockClock::new(4));
+        let chain = Chain::init_with(Arc::new(RwLock::new(config)), Arc::new(db), Box::new(KeyLottery), clock).unwrap();
+        let (_, genesis) = chain.get_status();
+        let events = chain.subscribe();
+
+        let future = signed_block(&chain, genesis, 5, &test_key(), Vec::new());
+        assert_eq!(chain.insert(future), Err(Error::FutureBlock));
+        let mut orphan = Block::init(2, 3, H256::from(9), Vec::new(), test_key().to_vec());
+        orphan.sign(&test_key());
+        assert_eq!(chain.insert(orphan), Err(Error::UnknownParent));
+        assert_eq!(events.try_iter().count(), 0);
+
+        let outsider = signed_block(&chain, genesis, 3, &H256::from(2), Vec::new());
+        assert_eq!(chain.insert(outsider.clone()), Err(Error::InvalidPublicKey));
+        let expected = vec![ChainEvent::BlockRejected { hash: outsider.hash(), error: Error::InvalidPublicKey }];
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn finalized_blocks_are_kept() {
+        let chain = test_chain();
+        let other = test_chain();
+        let (_, genesis) = chain.get_status();
+        assert_eq!(chain.finalized(), (0, genesis));
+
+        let mut hash = genesis;
+        let mut hashes = vec![genesis];
+        for i in 0..4 {
+            hash = chain.gen_block(i, hash, i + 1, vec![i as u8], vec![test_tx(i + 1)]).hash();
+            hashes.push(hash);
+        }
+        assert_eq!(chain.finalized(), (2, hashes[2]));
+        assert!(chain.is_final(&test_tx(1).hash()));
+        assert!(chain.is_final(&test_tx(2).hash()));
+        assert!(!chain.is_final(&test_tx(3).hash()));
+
+        let mut hash = genesis;
+        let mut fork = Vec::new();
+        for i in 0..5 {
+            let block = other.gen_block(i, hash, i + 10, vec![i as u8 + 10], Vec::new());
+            hash = block.hash();
+            fork.push(block);
+        }
+        for block in &fork {
+            import(&chain, block);
+        }
+        assert!(!chain.keeps_finalized(5, hash));
+        assert_eq!(chain.get_status(), (4, hashes[4]));
+        assert_eq!(chain.finalized(), (2, hashes[2]));
+    }
+
+    #[test]
+    fn validator_change_applies_from_next_epoch() {
+        let (a, b) = (test_key(), H256::from(2));
+        let chain = validator_chain(&[a]);
+        let mut parent = chain.get_status().1;
+        for t in 1..10 {
+            let mut txs = Vec::new();
+            if t == 3 {
+                let mut change = ValidatorChange::new(true, Validator::from(validator(&b)), t);
+                change.sign(&a);
+                let mut tx = SignedTransaction::new(t);
+                tx.set_data(change.to_data());
+                tx.signature = sign(&a, &tx.hash()).unwrap().into();
+                txs.push(tx);
+            }
+            if t > 3 {
+                // queued mid-epoch, b is not a validator before the boundary.
+                let early = signed_block(&chain, parent, t, &b, Vec::new());
+                assert_eq!(chain.insert(early), Err(Error::InvalidPublicKey));
+            }
+            let block = signed_block(&chain, parent, t, &a, txs);
+            chain.insert(block.clone()).unwrap();
+            parent = block.hash();
+        }
+        assert_eq!(chain.current_height(), 9);
+        assert_eq!(chain.validators_at(&parent).unwrap().len(), 2);
+
+        let block = signed_block(&chain, parent, 10, &b, Vec::new());
+        chain.insert(block.clone()).unwrap();
+        assert_eq!(chain.get_status(), (10, block.hash()));
+    }
+
+    #[test]
+    fn offender_is_removed_at_next_epoch() {
+        let (a, b) = (test_key(), H256::from(2));
+        let chain = validator_chain(&[a, b]);
+        let genesis = chain.get_status().1;
+        let x = signed_block(&chain, genesis, 1, &b, Vec::new());
+        let y = signed_block(&chain, genesis, 1, &b, vec![test_tx(1)]);
+        chain.insert(x.clone()).unwrap();
+        chain.insert(y.clone()).unwrap();
+        let evidence = chain.equivocations().pop().unwrap();
+        assert_eq!(chain.check_evidence(&evidence), Ok(()));
+
+        let mut parent = chain.get_status().1;
+        for t in 2..10 {
+            let txs = if t == 3 { vec![evidence.to_transaction(t, &a)] } else { Vec::new() };
+            if t > 3 {
+                // still active until the boundary.
+                let block = signed_block(&chain, parent, t, &b, Vec::new());
+                assert_eq!(chain.block_basic_check(&block), Ok(()));
+            }
+            let block = signed_block(&chain, parent, t, &a, txs);
+            chain.insert(block.clone()).unwrap();
+            parent = block.hash();
+            // already queued, reporting again is useless.
+            if t >= 3 {
+                assert_eq!(chain.check_evidence(&evidence), Err(Error::InvalidEvidence));
+            }
+        }
+        assert_eq!(chain.current_height(), 9);
+        assert_eq!(chain.validators_at(&parent).unwrap().len(), 1);
+
+        let late = signed_block(&chain, parent, 10, &b, Vec::new());
+        assert_eq!(chain.block_basic_check(&late), Err(Error::InvalidPublicKey));
+        let block = signed_block(&chain, parent, 10, &a, Vec::new());
+        assert_eq!(chain.block_basic_check(&block), Ok(()));
+    }
+
+    #[test]
+    fn default_consensus_roundtrip() {
+        let (private_key, public_key, g) = bls::key_gen();
+        let mut config = test_config(ForkChoiceRule::default());
+        config.miner_private_key = private_key;
+        let consensus = BlsLottery::new(Arc::new(RwLock::new(config)));
+        assert_eq!(consensus.anc_height(9), 0);
+        assert_eq!(consensus.anc_height(25), 10);
+
+        let mut header = Header::new();
+        header.height = 1;
+        header.timestamp = 1;
+        header.proof.time_signature = consensus.gen_proof(1, 1, H256::default());
+        assert!(consensus.verify_proof(&header, H256::default(), public_key.clone(), g.clone()));
+        assert!(!consensus.verify_proof(&header, H256::from(1), public_key, g));
+    }
+
+    #[test]
+    fn damaged_head_is_repaired() {
+        let chain = test_chain();
+        for t in 1..4 {
+            let (height, hash) = chain.get_status();
+            chain.gen_block(height, hash, t, Vec::new(), vec![test_tx(t)]);
+        }
+        let (height, head) = chain.get_status();
+        let parent = chain.get_block_header_by_hash(&head).unwrap().parent_hash;
+        let config = Arc::new(RwLock::new(test_config(ForkChoiceRule::default())));
+
+        // the head lost its number index.
+        let mut batch = chain.db.transaction();
+        Writable::delete::<H256, _>(&mut batch, db::COL_EXTRA, &height);
+        chain.db.write(batch).unwrap();
+        let restarted = Chain::init(config.clone(), chain.db.clone()).unwrap();
+        assert_eq!(restarted.get_status(), (2, parent));
+
+        // the head is unknown.
+        let mut batch = chain.db.transaction();
+        batch.put(db::COL_EXTRA, b"current_hash", &H256::from(7));
+        chain.db.write(batch).unwrap();
+        let restarted = Chain::init(config.clone(), chain.db.clone()).unwrap();
+        assert_eq!(restarted.get_status(), (2, parent));
+
+        // rebuilt from the indexed blocks when the head is unknown.
+        let mut batch = chain.db.transaction();
+        batch.put(db::COL_EXTRA, b"current_hash", &H256::from(7));
+        chain.db.write(batch).unwrap();
+        let report = repair::rebuild_indexes(&*chain.db, 2).unwrap();
+        assert_eq!(report, repair::RepairReport { head: (2, parent), dropped: 0, blocks: 3, transactions: 2 });
+
+        // rebuilt from the headers.
+        let mut batch = chain.db.transaction();
+        batch.put(db::COL_EXTRA, b"current_hash", &head);
+        chain.db.write(batch).unwrap();
+        let report = repair::rebuild_indexes(&*chain.db, 2).unwrap();
+        assert_eq!(report, repair::RepairReport { head: (3, head), dropped: 0, blocks: 4, transactions: 3 });
+        let restarted = Chain::init(config, chain.db.clone()).unwrap();
+        assert_eq!(restarted.get_status(), (3, head));
+        assert_eq!(restarted.get_transaction_address(&test_tx(3).hash()).unwrap().block_hash, head);
+    }
+
+    #[test]
+    fn tampered_block_from_peer_is_rejected() {
+        let key = test_key();
+        let chain = validator_chain(&[key]);
+        let genesis = chain.get_status().1;
+        let mut block = signed_block(&chain, genesis, 1, &key, vec![test_tx(1)]);
+        let hash = block.hash();
+
+        // the body and its root are replaced, the cached hash is sent as is.
+        block.body.transactions = Vec::new();
+        block.transactions_root = block.body.transactions_root();
+        assert_eq!(block.hash(), hash);
+        let received: Block = deserialize(&serialize(&block, Infinite).unwrap()).unwrap();
+        assert!(received.hash() != hash);
+        assert_eq!(chain.insert(received), Err(Error::InvalidPublicKey));
+        assert_eq!(chain.current_height(), 0);
+    }
+
+    #[test]
+    fn unloadable_chain_is_an_error() {
+        let chain = test_chain();
+        let config = Arc::new(RwLock::new(test_config(ForkChoiceRule::default())));
+        let mut batch = chain.db.transaction();
+        batch.put(db::COL_EXTRA, b"current_hash", &H256::from(7));
+        Writable::delete::<H256, _>(&mut batch, db::COL_EXTRA, &0u64);
+        chain.db.write(batch).unwrap();
+        assert_eq!(Chain::init(config, chain.db.clone()).err(), Some(Error::UnknownAncestor));
+    }
+
+    #[test]
+    fn long_fork_replaces_best_chain() {
+        let key = test_key();
+        let mut config = test_config(ForkChoiceRule::EarliestTimestamp);
+        config.keygroups = vec![validator(&key)];
+        config.finality_depth = 7;
+        let db = in_memory(db::NUM_COLUMNS.unwrap());
+        let chain = Chain::init_with_consensus(Arc::new(RwLock::new(config)), Arc::new(db), Box::new(KeyLottery)).unwrap();
+        let mut parent = chain.get_status().1;
+        let mut main = Vec::new();
+        for t in 1..9 {
+            let block = signed_block(&chain, parent, t, &key, Vec::new());
+            chain.insert(block.clone()).unwrap();
+            parent = block.hash();
+            main.push(block.hash());
+        }
+        assert_eq!(chain.finalized(), (1, main[0]));
+
+        // deeper than the buffer size below the best block, stored unverified,
+        // and switched to once longer.
+        let mut parent = main[0];
+        let mut fork = Vec::new();
+        for t in 11..19 {
+            let block = signed_block(&chain, parent, t, &key, vec![test_tx(t)]);
+            chain.insert(block.clone()).unwrap();
+            parent = block.hash();
+            fork.push(block.hash());
+        }
+        assert_eq!(chain.get_status(), (9, parent));
+        assert_eq!(chain.block_hash_by_number(2), Some(fork[0]));
+        assert_eq!(chain.finalized(), (2, fork[0]));
+        assert_eq!(chain.get_transaction_address(&test_tx(11).hash()).unwrap().block_hash, fork[0]);
+        let report = verify::verify_chain(&chain, &chain.config.read());
+        assert_eq!(report.invalid, None);
+    }
+
+    #[test]
+    fn concurrent_inserts_import_once() {
+        let key = test_key();
+        let miner = validator_chain(&[key]);
+        let node = validator_chain(&[key]);
+        for t in 1..6 {
+            let parent = miner.get_status().1;
+            let block = signed_block(&miner, parent, t, &key, vec![test_tx(t)]);
+            miner.insert(block.clone()).unwrap();
+            let threads: Vec<_> = (0..2).map(|_| {
+                let (node, block) = (node.clone(), block.clone());
+                thread::spawn(move || node.insert(block))
+            }).collect();
+            let mut results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+            results.sort_by_key(|r| r.is_ok());
+            assert_eq!(results, vec![Err(Error::DuplicateBlock), Ok(())]);
+        }
+        assert_eq!(node.get_status(), miner.get_status());
+        assert_eq!(node.get_transaction_address(&test_tx(5).hash()).unwrap().block_hash, node.get_status().1);
+    }
+
+    #[test]
+    fn verify_chain_reports_first_invalid_block() {
+        let chain = test_chain();
+        let config = test_config(ForkChoiceRule::default());
+        let (_, genesis) = chain.get_status();
+        let report = verify::verify_chain(&chain, &config);
+        assert_eq!((report.blocks, report.invalid), (1, None));
+
+        // made up proofs lose the lottery.
+        let block = chain.gen_block(0, genesis, 1, Vec::new(), vec![test_tx(1)]);
+        chain.gen_block(1, block.hash(), 2, Vec::new(), Vec::new());
+        let report = verify::verify_chain(&chain, &config);
+        assert_eq!(report.blocks, 1);
+        assert_eq!(report.invalid, Some((1, block.hash(), Error::InvalidProof)));
+    }
+
+    #[test]
+    fn verify_chain_accepts_imported_blocks() {
+        let (a, b) = (test_key(), H256::from(2));
+        let chain = validator_chain(&[a, b]);
+        let mut parent = chain.get_status().1;
+        // across an epoch boundary.
+        for t in 1..13 {
+            let key = if t % 2 == 0 { &a } else { &b };
+            let block = signed_block(&chain, parent, t, key, vec![test_tx(t)]);
+            chain.insert(block.clone()).unwrap();
+            parent = block.hash();
+        }
+        let report = verify::verify_chain(&chain, &chain.config.read());
+        assert_eq!(report, verify::VerifyReport { blocks: 13, transactions: 12, unverified: 0, invalid: None });
+    }
+}
@@ -0,0 +1,67 @@
+//! Consensus engines deciding who may produce a block.
+
+use std::sync::Arc;
+use parking_lot::RwLock;
+use util::{H256, U256, Hashable};
+use util::config::SleepyConfig;
+use block::{Block, Header};
+
+/// Lottery rule of a consensus engine.
+pub trait Consensus: Send + Sync {
+    /// Height of the ancestor seeding the lottery for a block at `height`.
+    fn anc_height(&self, height: u64) -> u64;
+
+    /// Lottery seed taken from the ancestor header.
+    fn seed(&self, ancestor: &Header) -> H256;
+
+    /// Generate our proof for the slot at `time` and `height`.
+    fn gen_proof(&self, time: u64, height: u64, seed: H256) -> Vec<u8>;
+
+    /// Whether the proof wins the lottery.
+    fn is_eligible(&self, proof: &[u8]) -> bool;
+
+    /// Verify the header's proof against the proof keys of its signer.
+    fn verify_proof(&self, header: &Header, seed: H256, public_key: Vec<u8>, g: Vec<u8>) -> bool;
+}
+
+/// BLS signature lottery, the default engine.
+///
+/// A miner wins a slot when the hash of its BLS signature over
+/// (time, height, seed) is below the difficulty, the seed being the
+/// proof hash of the first block of the previous epoch.
+pub struct BlsLottery {
+    config: Arc<RwLock<SleepyConfig>>,
+}
+
+impl BlsLottery {
+    pub fn new(config: Arc<RwLock<SleepyConfig>>) -> Self {
+        BlsLottery { config: config }
+    }
+}
+
+impl Consensus for BlsLottery {
+    fn anc_height(&self, height: u64) -> u64 {
+        let len = {self.config.read().epoch_len};
+        let mut a = height / len;
+        if a > 0 { a -= 1}
+        a * len
+    }
+
+    fn seed(&self, ancestor: &Header) -> H256 {
+        ancestor.proof.time_signature.sha3()
+    }
+
+    fn gen_proof(&self, time: u64, height: u64, seed: H256) -> Vec<u8> {
+        let miner_privkey = {self.config.read().get_miner_private_key()};
+        Block::gen_proof(miner_privkey, time, height, seed)
+    }
+
+    fn is_eligible(&self, proof: &[u8]) -> bool {
+        let value: U256 = proof.sha3().into();
+        value < self.config.read().get_difficulty()
+    }
+
+    fn verify_proof(&self, header: &Header, seed: H256, public_key: Vec<u8>, g: Vec<u8>) -> bool {
+        header.verify_proof(seed, public_key, g)
+    }
+}
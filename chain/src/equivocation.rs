@@ -0,0 +1,98 @@
+//! Miner equivocation evidence.
+//!
+//! A miner equivocates when it signs two different headers for the same
+//! timestamp. Headers are indexed by (signer, timestamp) when imported, and
+//! a second header for a known slot is kept along with the first one.
+//!
+//! Miners include the evidence in a transaction whose data is prefixed by
+//! `EVIDENCE_PREFIX`. Once applied, the offender is removed from the
+//! validator set at the next epoch boundary.
+
+use util::{H256, H512};
+use crypto::sign;
+use block::Header;
+use transaction::{SignedTransaction, Transaction};
+use validator::ValidatorSet;
+use error::Error;
+use rlp;
+
+/// Prefix of the transaction data carrying equivocation evidence.
+pub const EVIDENCE_PREFIX: &'static [u8] = b"sleepy/equivocation-evidence";
+
+/// Two headers signed by the same key for the same timestamp.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, RlpEncodable, RlpDecodable)]
+pub struct Equivocation {
+    pub signer: H512,
+    pub timestamp: u64,
+    /// The header seen first.
+    pub first: Header,
+    pub second: Header,
+}
+
+impl Equivocation {
+    pub fn new(signer: H512, first: Header, second: Header) -> Self {
+        // fill the hash caches, they are part of the serialized headers.
+        first.hash();
+        second.hash();
+        Equivocation {
+            signer: signer,
+            timestamp: first.timestamp,
+            first: first,
+            second: second,
+        }
+    }
+
+    /// Hashes of both headers, in the order they were seen.
+    pub fn hashes(&self) -> (H256, H256) {
+        (self.first.hash(), self.second.hash())
+    }
+
+    /// Whether both headers are validly signed by `signer` for the same timestamp.
+    pub fn is_valid(&self) -> bool {
+        let (first, second) = self.hashes();
+        first != second &&
+            self.first.timestamp == self.timestamp &&
+            self.second.timestamp == self.timestamp &&
+            self.first.sign_public() == Ok(self.signer) &&
+            self.second.sign_public() == Ok(self.signer)
+    }
+
+    /// Check the evidence against the active validator set.
+    pub fn verify(&self, set: &ValidatorSet) -> Result<(), Error> {
+        if !set.contains(&self.signer) || !self.is_valid() {
+            return Err(Error::InvalidEvidence);
+        }
+        Ok(())
+    }
+
+    /// Encode into transaction data.
+    pub fn to_data(&self) -> Vec<u8> {
+        let mut data = EVIDENCE_PREFIX.to_vec();
+        data.extend_from_slice(&rlp::encode(self));
+        data
+    }
+
+    /// Decode from transaction data, `None` if the data is not evidence.
+    pub fn from_data(data: &[u8]) -> Option<Result<Self, Error>> {
+        if !data.starts_with(EVIDENCE_PREFIX) {
+            return None;
+        }
+        let raw = &data[EVIDENCE_PREFIX.len()..];
+        Some(rlp::UntrustedRlp::new(raw).as_val().map_err(|_| Error::InvalidFormat))
+    }
+
+    /// Build the evidence transaction, signed by the reporting miner.
+    pub fn to_transaction(&self, timestamp: u64, privkey: &H256) -> SignedTransaction {
+        let tx = Transaction {
+            data: self.to_data(),
+            timestamp: timestamp,
+        };
+        let hash = tx.cal_hash();
+        let sig = sign(privkey, &hash).expect("failed to sign evidence transaction");
+        SignedTransaction {
+            transaction: tx,
+            hash: hash,
+            signature: sig.into(),
+        }
+    }
+}
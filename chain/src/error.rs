@@ -1,4 +1,4 @@
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
     FutureBlock,
     UnknownParent,
@@ -9,12 +9,20 @@ pub enum Error {
     InvalidReceiptsRoot,
     InvalidStateRoot,
     InvalidTransactionsRoot,
+    InvalidTransactionHash,
     InvalidPublicKey,
     InvalidProofKey,
     InvalidProof,
     InvalidSignature,
     InvalidFormat,
+    InvalidValidatorChange,
+    InvalidEvidence,
     LongFork,
+    RevertFinalized,
     UnknownAncestor,
+    UnknownValidators,
     NTPError,
+    Database(String),
+    SchemaTooNew(u32),
+    MigrationFailed(u32, String),
 }
\ No newline at end of file
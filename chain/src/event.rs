@@ -0,0 +1,59 @@
+//! Chain events delivered to subscribers.
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use util::{H256, Mutex};
+use error::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainEvent {
+    /// A block was written to the db, whether it became the head or not.
+    BlockImported { hash: H256, height: u64 },
+    /// The best block changed.
+    NewHead { hash: H256, height: u64 },
+    /// Blocks left the canonical chain, oldest first on both sides.
+    Reorg { retracted: Vec<H256>, enacted: Vec<H256> },
+    /// A block failed to import.
+    BlockRejected { hash: H256, error: Error },
+}
+
+/// Fan-out of chain events, each subscriber gets its own channel.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Sender<ChainEvent>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus::default()
+    }
+
+    pub fn subscribe(&self) -> Receiver<ChainEvent> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().push(tx);
+        rx
+    }
+
+    /// Send the event to all subscribers, dropping the ones that hung up.
+    pub fn notify(&self, event: ChainEvent) {
+        self.subscribers.lock().retain(|s| s.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_subscriber_gets_events() {
+        let bus = EventBus::new();
+        let a = bus.subscribe();
+        let b = bus.subscribe();
+        drop(bus.subscribe());
+
+        let event = ChainEvent::NewHead { hash: H256::from(1), height: 1 };
+        bus.notify(event.clone());
+        assert_eq!(a.try_recv(), Ok(event.clone()));
+        assert_eq!(b.try_recv(), Ok(event));
+        assert_eq!(bus.subscribers.lock().len(), 2);
+    }
+}
@@ -0,0 +1,96 @@
+//! Portable chain files.
+//!
+//! A file is a sequence of canonical blocks, each one written as a 4 byte
+//! big endian length followed by the RLP list `[header, body]`.
+
+use std::io::{self, Read, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rlp::{RlpStream, UntrustedRlp};
+use block::Block;
+use chain::Chain;
+use error::Error;
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    /// The block at given height was rejected.
+    Block(u64, Error),
+}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> Self {
+        ImportError::Io(e)
+    }
+}
+
+fn encode(block: &Block) -> Vec<u8> {
+    let mut s = RlpStream::new_list(2);
+    s.append(&block.header);
+    s.append(&block.body);
+    s.out()
+}
+
+fn decode(bytes: &[u8]) -> io::Result<Block> {
+    let rlp = UntrustedRlp::new(bytes);
+    let invalid = |_| io::Error::new(io::ErrorKind::InvalidData, "invalid block rlp");
+    Ok(Block {
+        header: rlp.val_at(0).map_err(invalid)?,
+        body: rlp.val_at(1).map_err(invalid)?,
+    })
+}
+
+/// Write the canonical blocks from `from` to `to` included, returns the number written.
+pub fn export_blocks<W: Write>(chain: &Chain, from: u64, to: u64, w: &mut W) -> io::Result<u64> {
+    let mut n = 0;
+    for height in from..(to + 1) {
+        let block = match chain.block_hash_by_number(height).and_then(|h| chain.get_block_by_hash(&h)) {
+            Some(b) => b,
+            None => break,
+        };
+        let bytes = encode(&block);
+        w.write_u32::<BigEndian>(bytes.len() as u32)?;
+        w.write_all(&bytes)?;
+        n += 1;
+    }
+    w.flush()?;
+    Ok(n)
+}
+
+/// Read one block, `None` at the end of the stream.
+pub fn read_block<R: Read>(r: &mut R) -> io::Result<Option<Block>> {
+    let len = match r.read_u32::<BigEndian>() {
+        Ok(len) => len,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut bytes = vec![0u8; len as usize];
+    r.read_exact(&mut bytes)?;
+    decode(&bytes).map(Some)
+}
+
+/// Import the blocks of a file, returns the number of new blocks.
+///
+/// Blocks we already have are skipped. With `check_proofs` false the file
+/// is trusted and only transactions and state are checked.
+pub fn import_blocks<R: Read>(chain: &Chain, r: &mut R, check_proofs: bool) -> Result<u64, ImportError> {
+    let mut n = 0;
+    while let Some(block) = read_block(r)? {
+        let height = block.height;
+        if height == 0 {
+            if Some(block.hash()) != chain.block_hash_by_number(0) {
+                return Err(ImportError::Block(0, Error::InvalidFormat));
+            }
+            continue;
+        }
+        let ret = match check_proofs {
+            true => chain.insert(block),
+            false => chain.insert_trusted(block),
+        };
+        match ret {
+            Ok(_) => n += 1,
+            Err(Error::DuplicateBlock) => {}
+            Err(e) => return Err(ImportError::Block(height, e)),
+        }
+    }
+    Ok(n)
+}
@@ -20,9 +20,10 @@ use std::ops;
 // use std::io::Write;
 use db::Key;
 use block::{BlockNumber, RichHeader, Header, Body};
+use receipt::BlockReceipts;
 
 use heapsize::HeapSizeOf;
-use bigint::hash::{H256, H264};
+use bigint::hash::{H256, H264, H512};
 // use kvdb::PREFIX_LEN as DB_PREFIX_LEN;
 
 /// Represents index of extra data in database
@@ -32,6 +33,10 @@ pub enum ExtrasIndex {
     BlockHash = 1,
     /// Transaction address index
     TransactionAddress = 2,
+    /// Block receipts index
+    BlockReceipts = 3,
+    /// Header hash by signer and timestamp index
+    SignerSlot = 4,
 }
 
 fn with_index(hash: &H256, i: ExtrasIndex) -> H264 {
@@ -97,6 +102,45 @@ impl Key<TransactionAddress> for H256 {
     }
 }
 
+impl Key<BlockReceipts> for H256 {
+    type Target = H264;
+
+    fn key(&self) -> H264 {
+        with_index(self, ExtrasIndex::BlockReceipts)
+    }
+}
+
+/// Signer and timestamp of a header, at most one header may be signed per slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignerSlot {
+    pub signer: H512,
+    pub timestamp: u64,
+}
+
+pub struct SignerSlotKey([u8; 73]);
+
+impl ops::Deref for SignerSlotKey {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Key<H256> for SignerSlot {
+    type Target = SignerSlotKey;
+
+    fn key(&self) -> Self::Target {
+        let mut result = [0u8; 73];
+        result[0] = ExtrasIndex::SignerSlot as u8;
+        result[1..65].clone_from_slice(&self.signer);
+        for i in 0..8 {
+            result[65 + i] = (self.timestamp >> (56 - 8 * i)) as u8;
+        }
+        SignerSlotKey(result)
+    }
+}
+
 /// Represents address of certain transaction within block
 #[derive(Debug, PartialEq, Clone, RlpEncodable, RlpDecodable)]
 pub struct TransactionAddress {
@@ -0,0 +1,65 @@
+//! Rules choosing between competing blocks at the same height.
+
+use util::config::ForkChoiceRule;
+use block::Header;
+
+/// Deterministic fork choice between two blocks at the same height.
+///
+/// Every node must pick the same block whatever the arrival order,
+/// so a rule has to be a strict total order over headers.
+pub trait ForkChoice: Send + Sync {
+    /// Whether `candidate` should replace `current` as the best block.
+    fn prefer(&self, candidate: &Header, current: &Header) -> bool;
+}
+
+/// Prefer the block with the lowest lottery proof value.
+pub struct LowestProof;
+
+impl ForkChoice for LowestProof {
+    fn prefer(&self, candidate: &Header, current: &Header) -> bool {
+        (candidate.difficulty(), candidate.hash()) < (current.difficulty(), current.hash())
+    }
+}
+
+/// Prefer the block with the earliest timestamp, then the lowest proof value.
+pub struct EarliestTimestamp;
+
+impl ForkChoice for EarliestTimestamp {
+    fn prefer(&self, candidate: &Header, current: &Header) -> bool {
+        (candidate.timestamp, candidate.difficulty(), candidate.hash()) <
+        (current.timestamp, current.difficulty(), current.hash())
+    }
+}
+
+/// Get the fork choice selected in config.
+pub fn from_rule(rule: ForkChoiceRule) -> Box<ForkChoice> {
+    match rule {
+        ForkChoiceRule::LowestProof => Box::new(LowestProof),
+        ForkChoiceRule::EarliestTimestamp => Box::new(EarliestTimestamp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(timestamp: u64, proof: u8) -> Header {
+        let mut header = Header::new();
+        header.height = 1;
+        header.timestamp = timestamp;
+        header.proof.time_signature = vec![proof];
+        header
+    }
+
+    #[test]
+    fn rules_are_antisymmetric() {
+        let a = header(1, 1);
+        let b = header(2, 2);
+        for rule in vec![from_rule(ForkChoiceRule::LowestProof), from_rule(ForkChoiceRule::EarliestTimestamp)] {
+            assert!(rule.prefer(&a, &b) != rule.prefer(&b, &a));
+            assert!(!rule.prefer(&a, &a));
+        }
+        assert!(EarliestTimestamp.prefer(&a, &b));
+        assert_eq!(LowestProof.prefer(&a, &b), a.difficulty() < b.difficulty());
+    }
+}
@@ -4,7 +4,6 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate parking_lot;
-extern crate rand;
 #[macro_use]
 extern crate log;
 extern crate bincode;
@@ -16,6 +15,9 @@ extern crate kvdb;
 extern crate heapsize;
 extern crate bigint;
 extern crate ethcore_bytes as bytes;
+extern crate byteorder;
+#[cfg(test)]
+extern crate ethcore_devtools as devtools;
 
 pub mod error;
 pub mod block;
@@ -25,3 +27,16 @@ pub mod extras;
 pub mod db;
 pub mod cache_manager;
 pub mod cache;
+pub mod state;
+pub mod receipt;
+pub mod fork_choice;
+pub mod consensus;
+pub mod validator;
+pub mod block_buffer;
+pub mod event;
+pub mod light;
+pub mod export;
+pub mod equivocation;
+pub mod migration;
+pub mod repair;
+pub mod verify;
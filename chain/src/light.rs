@@ -0,0 +1,390 @@
+//! Light chain following headers only.
+//!
+//! Headers are kept in `COL_LIGHT_CHAIN` and checked like full blocks:
+//! block signature, BLS proof and fork choice. There is no state: the
+//! validator set of an epoch is imported with the trie nodes proving it
+//! against the state root of the epoch's last block, the genesis one comes
+//! from the config keygroups. Transactions are checked through the inclusion
+//! proofs full nodes serve.
+
+use std::sync::Arc;
+use util::{H256, Bytes, RwLock, ProofNode, verify_merkle_proof};
+use util::config::SleepyConfig;
+use util::clock::{self, Clock};
+use kvdb::{DBTransaction, KeyValueDB};
+use block::{Header, BlockNumber};
+use chain::genesis;
+use consensus::{Consensus, BlsLottery};
+use fork_choice::{self, ForkChoice};
+use state::verify_validators_proof;
+use validator::{validators_height, ValidatorSet};
+use db::{self, Writable, Readable};
+use error::Error;
+use rlp;
+
+fn validators_key(hash: &H256) -> Vec<u8> {
+    let mut key = b"validators".to_vec();
+    key.extend_from_slice(hash);
+    key
+}
+
+pub struct LightChain {
+    db: Arc<KeyValueDB>,
+    best: RwLock<(BlockNumber, H256)>,
+    config: Arc<RwLock<SleepyConfig>>,
+    fork_choice: Box<ForkChoice>,
+    consensus: Box<Consensus>,
+    clock: Arc<Clock>,
+}
+
+impl LightChain {
+    pub fn init(config: Arc<RwLock<SleepyConfig>>, db: Arc<KeyValueDB>) -> Self {
+        let consensus = Box::new(BlsLottery::new(config.clone()));
+        let fork_choice = fork_choice::from_rule({config.read().fork_choice});
+        let clock = clock::from_config(&config.read());
+        let chain = LightChain {
+            db: db,
+            best: RwLock::new((0, H256::default())),
+            config: config,
+            fork_choice: fork_choice,
+            consensus: consensus,
+            clock: clock,
+        };
+
+        let best = chain.db.get(db::COL_LIGHT_CHAIN, b"best_hash").unwrap().map(|h| H256::from_slice(&h));
+        let best = match best {
+            Some(hash) => {
+                let header = chain.get_header(&hash).expect("best header not found!");
+                (header.height, hash)
+            }
+            None => {
+                // the genesis state is built in memory only, for its root.
+                let (genesis, _) = genesis(&chain.config.read(), chain.db.clone());
+                let mut batch = chain.db.transaction();
+                chain.write_header(&mut batch, &genesis.header);
+                chain.set_best(&mut batch, &genesis.header);
+                chain.db.write(batch).expect("DB write failed.");
+                (0, genesis.hash())
+            }
+        };
+        *chain.best.write() = best;
+
+        // light databases written before validator sets were imported have no genesis set.
+        let genesis_hash = chain.block_hash_by_number(0).expect("genesis not found!");
+        if chain.validators_at(&genesis_hash).is_none() {
+            let validators = ValidatorSet::from(chain.config.read().get_keygroups().clone());
+            let mut batch = chain.db.transaction();
+            batch.put(db::COL_LIGHT_CHAIN, &validators_key(&genesis_hash), &rlp::encode(&validators));
+            chain.db.write(batch).expect("DB write failed.");
+        }
+        chain
+    }
+
+    pub fn get_status(&self) -> (BlockNumber, H256) {
+        *self.best.read()
+    }
+
+    pub fn get_header(&self, hash: &H256) -> Option<Header> {
+        let header: Header = self.db.read(db::COL_LIGHT_CHAIN, hash)?;
+        header.hash.set(Some(*hash));
+        Some(header)
+    }
+
+    pub fn block_hash_by_number(&self, number: BlockNumber) -> Option<H256> {
+        self.db.read(db::COL_LIGHT_CHAIN, &number)
+    }
+
+    pub fn get_header_by_number(&self, number: BlockNumber) -> Option<Header> {
+        self.block_hash_by_number(number).and_then(|h| self.get_header(&h))
+    }
+
+    /// Hash of the ancestor at `number` of the block `hash` at `height`.
+    fn block_hash_by_number_fork(&self, number: BlockNumber, mut height: BlockNumber, mut hash: H256) -> Option<H256> {
+        while height > number {
+            if self.block_hash_by_number(height) == Some(hash) {
+                return self.block_hash_by_number(number);
+            }
+            hash = self.get_header(&hash)?.parent_hash;
+            height -= 1;
+        }
+        Some(hash)
+    }
+
+    /// Validator set active for the children of given block, once imported.
+    pub fn validators_at(&self, hash: &H256) -> Option<ValidatorSet> {
+        let value = self.db.get(db::COL_LIGHT_CHAIN, &validators_key(hash)).expect("DB read failed.")?;
+        Some(rlp::decode(&value))
+    }
+
+    /// Hash of the block holding the validator set of `header`, on its branch.
+    ///
+    /// The parent of the header must be known.
+    pub fn epoch_hash(&self, header: &Header) -> Option<H256> {
+        let epoch_len = { self.config.read().epoch_len };
+        let height = validators_height(header.height, epoch_len);
+        self.block_hash_by_number_fork(height, header.height - 1, header.parent_hash)
+    }
+
+    /// Import the validator set in the state of a known block, from the trie
+    /// nodes a full node serves for it.
+    pub fn import_validators(&self, hash: &H256, proof: &[Bytes]) -> Result<(), Error> {
+        let header = self.get_header(hash).ok_or(Error::UnknownAncestor)?;
+        let validators = match verify_validators_proof(&header.state_root, proof)? {
+            Some(v) => v,
+            // a chain created without keygroups.
+            None => ValidatorSet::from(self.config.read().get_keygroups().clone()),
+        };
+        let mut batch = self.db.transaction();
+        batch.put(db::COL_LIGHT_CHAIN, &validators_key(hash), &rlp::encode(&validators));
+        self.db.write(batch).map_err(Error::Database)
+    }
+
+    /// Check a header on top of a known parent.
+    ///
+    /// Fails with `UnknownValidators` until the validator set of its epoch is
+    /// imported, see `epoch_hash` and `import_validators`.
+    pub fn verify(&self, header: &Header) -> Result<(), Error> {
+        header.check_hash()?;
+        let hash = header.hash();
+        if self.get_header(&hash).is_some() {
+            return Err(Error::DuplicateBlock);
+        }
+        let parent = self.get_header(&header.parent_hash).ok_or(Error::UnknownParent)?;
+        if header.height != parent.height + 1 || header.timestamp <= parent.timestamp {
+            return Err(Error::InvalidTimestamp);
+        }
+        if !self.consensus.is_eligible(&header.proof.time_signature) {
+            return Err(Error::InvalidProof);
+        }
+
+        let anc_height = self.consensus.anc_height(header.height);
+        let anc_hash = self.block_hash_by_number_fork(anc_height, parent.height, parent.hash()).ok_or(Error::UnknownAncestor)?;
+        let seed = self.consensus.seed(&self.get_header(&anc_hash).ok_or(Error::UnknownAncestor)?);
+
+        let epoch_hash = self.epoch_hash(header).ok_or(Error::UnknownAncestor)?;
+        let validators = self.validators_at(&epoch_hash).ok_or(Error::UnknownValidators)?;
+        let sign_pub = header.sign_public()?;
+        let (proof_pub, proof_g) = validators.proof_keys(&sign_pub).ok_or(Error::InvalidPublicKey)?;
+        if !self.consensus.verify_proof(header, seed, proof_pub, proof_g) {
+            return Err(Error::InvalidProofKey);
+        }
+
+        let now = self.clock.now().ok_or(Error::NTPError)?;
+        if header.timestamp > now {
+            return Err(Error::FutureBlock);
+        }
+        Ok(())
+    }
+
+    /// Verify and store a header, it becomes the best one if preferred.
+    pub fn insert(&self, header: Header) -> Result<(), Error> {
+        self.verify(&header)?;
+        self.insert_verified(header);
+        Ok(())
+    }
+
+    fn insert_verified(&self, header: Header) {
+        let mut batch = self.db.transaction();
+        self.write_header(&mut batch, &header);
+
+        let (best_height, best_hash) = self.get_status();
+        let adopt = header.height > best_height || (header.height == best_height && match self.get_header(&best_hash) {
+            Some(best) => self.fork_choice.prefer(&header, &best),
+            None => true,
+        });
+        if adopt {
+            self.set_best(&mut batch, &header);
+        }
+        self.db.write(batch).expect("DB write failed.");
+        if adopt {
+            *self.best.write() = (header.height, header.hash());
+        }
+    }
+
+    fn write_header(&self, batch: &mut DBTransaction, header: &Header) {
+        batch.write(db::COL_LIGHT_CHAIN, &header.hash(), header);
+    }
+
+    /// Make the header the best one and index its branch by number.
+    ///
+    /// The caller updates `best` once the batch is written.
+    fn set_best(&self, batch: &mut DBTransaction, header: &Header) {
+        let hash = header.hash();
+        let mut height = header.height;
+        let mut current = hash;
+        batch.write(db::COL_LIGHT_CHAIN, &height, &current);
+        while height > 0 {
+            let parent = match self.get_header(&current) {
+                Some(h) => h.parent_hash,
+                None => header.parent_hash,
+            };
+            height -= 1;
+            if self.block_hash_by_number(height) == Some(parent) {
+                break;
+            }
+            batch.write(db::COL_LIGHT_CHAIN, &height, &parent);
+            current = parent;
+        }
+        batch.put(db::COL_LIGHT_CHAIN, b"best_hash", &hash);
+    }
+
+    /// Check a transaction inclusion proof served by a full node, the block
+    /// must be on the best chain.
+    pub fn verify_transaction(&self, tx_hash: &H256, block_hash: &H256, proof: &[ProofNode]) -> bool {
+        match self.get_header(block_hash) {
+            Some(ref header) if self.block_hash_by_number(header.height) == Some(*block_hash) => {
+                verify_merkle_proof(&header.transactions_root, tx_hash, proof)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use kvdb::in_memory;
+    use util::config::{Config, KeyGroup, ForkChoiceRule, BlockBufferConfig, RpcConfig, ClockSource, ByzantineConfig};
+    use crypto::{sign, KeyPair};
+    use bls;
+    use block::Block;
+    use chain::Chain;
+    use transaction::SignedTransaction;
+    use validator::{Validator, ValidatorChange};
+
+    /// Every proof wins the lottery.
+    fn test_config(keygroups: Vec<KeyGroup>) -> SleepyConfig {
+        let config = Config {
+            id_card: 0,
+            port: 40000,
+            max_peer: 0,
+            steps: 1,
+            nps: 1,
+            miner_private_key: Vec::new(),
+            signer_private_key: H256::default(),
+            peers: Vec::new(),
+            keygroups: keygroups,
+            epoch_len: 5,
+            start_time: 0,
+            ntp_servers: Vec::new(),
+            buffer_size: 5,
+            fork_choice: ForkChoiceRule::default(),
+            finality_depth: 2,
+            block_buffer: BlockBufferConfig::default(),
+            rpc: RpcConfig::default(),
+            clock: ClockSource::System,
+            byzantine: ByzantineConfig::default(),
+        };
+        SleepyConfig { config: config, public_keys: HashMap::new() }
+    }
+
+    fn light_chain(config: SleepyConfig) -> LightChain {
+        LightChain::init(Arc::new(RwLock::new(config)), Arc::new(in_memory(db::NUM_COLUMNS.unwrap())))
+    }
+
+    /// BLS and signer keys of a miner.
+    fn miner(signer: u64) -> (Vec<u8>, H256, KeyGroup) {
+        let (private_key, public_key, g) = bls::key_gen();
+        let signer = H256::from(signer);
+        let group = KeyGroup {
+            proof_public_key: public_key,
+            proof_public_g: g,
+            signer_public_key: *KeyPair::from_privkey(signer).unwrap().pubkey(),
+        };
+        (private_key, signer, group)
+    }
+
+    fn mine(chain: &Chain, t: u64, txs: Vec<SignedTransaction>) -> Block {
+        let (height, hash) = chain.get_status();
+        let seed = chain.anc_hash(height, hash).unwrap();
+        let proof = chain.consensus().gen_proof(t, height + 1, seed);
+        chain.gen_block(height, hash, t, proof, txs)
+    }
+
+    #[test]
+    fn follows_headers_and_checks_inclusion() {
+        let chain = light_chain(test_config(Vec::new()));
+        let (_, genesis) = chain.get_status();
+        let txs: Vec<SignedTransaction> = (1..4).map(SignedTransaction::new).collect();
+        let a = Block::init(1, 1, genesis, txs.clone(), vec![1]);
+        let b = Block::init(1, 2, genesis, Vec::new(), vec![2]);
+        let c = Block::init(2, 3, b.hash(), Vec::new(), vec![3]);
+
+        let orphan = Block::init(2, 2, H256::from(1), Vec::new(), vec![4]);
+        assert_eq!(chain.verify(&orphan.header), Err(Error::UnknownParent));
+        chain.insert_verified(a.header.clone());
+        assert_eq!(chain.get_status(), (1, a.hash()));
+
+        let proof = a.body.transaction_proof(1).unwrap();
+        assert!(chain.verify_transaction(&txs[1].hash(), &a.hash(), &proof));
+        assert!(!chain.verify_transaction(&txs[0].hash(), &a.hash(), &proof));
+        assert!(!chain.verify_transaction(&txs[1].hash(), &H256::from(1), &proof));
+
+        chain.insert_verified(b.header.clone());
+        chain.insert_verified(c.header.clone());
+        assert_eq!(chain.get_status(), (2, c.hash()));
+        assert_eq!(chain.block_hash_by_number(1), Some(b.hash()));
+        assert_eq!(chain.verify(&c.header), Err(Error::DuplicateBlock));
+        // a is no longer on the best chain.
+        assert!(!chain.verify_transaction(&txs[1].hash(), &a.hash(), &proof));
+    }
+
+    #[test]
+    fn follows_a_full_chain_across_validator_changes() {
+        let (a_miner, a_signer, a_group) = miner(1);
+        let (b_miner, b_signer, b_group) = miner(2);
+        let light = light_chain(test_config(vec![a_group.clone()]));
+        let mut config = test_config(vec![a_group]);
+        config.miner_private_key = a_miner;
+        config.signer_private_key = a_signer;
+        let config = Arc::new(RwLock::new(config));
+        let full = Chain::init(config.clone(), Arc::new(in_memory(db::NUM_COLUMNS.unwrap()))).unwrap();
+        assert_eq!(light.get_status(), full.get_status());
+
+        // b joins in block 1 and mines from the second epoch.
+        let mut change = ValidatorChange::new(true, Validator::from(b_group), 1);
+        change.sign(&a_signer);
+        let mut tx = SignedTransaction::new(1);
+        tx.set_data(change.to_data());
+        tx.signature = sign(&a_signer, &tx.hash()).unwrap().into();
+        let mut blocks = vec![mine(&full, 1, vec![tx.clone()])];
+        for t in 2..5 {
+            blocks.push(mine(&full, t, Vec::new()));
+        }
+        {
+            let mut config = config.write();
+            config.miner_private_key = b_miner;
+            config.signer_private_key = b_signer;
+        }
+        for t in 5..7 {
+            blocks.push(mine(&full, t, Vec::new()));
+        }
+        assert_eq!(full.get_status(), (6, blocks[5].hash()));
+
+        // a forged root under the hash of the genuine header.
+        let mut forged = blocks[0].header.clone();
+        forged.transactions_root = H256::from(1);
+        assert_eq!(forged.hash(), blocks[0].hash());
+        assert_eq!(light.insert(forged), Err(Error::InvalidFormat));
+
+        for block in &blocks[..4] {
+            light.insert(block.header.clone()).unwrap();
+        }
+        let epoch = light.epoch_hash(&blocks[4].header).unwrap();
+        assert_eq!(epoch, blocks[3].hash());
+        assert_eq!(light.insert(blocks[4].header.clone()), Err(Error::UnknownValidators));
+
+        // the set of an older state does not match the epoch's root.
+        let stale = full.get_validators_proof(&blocks[2].hash()).unwrap();
+        assert!(light.import_validators(&epoch, &stale).is_err());
+        light.import_validators(&epoch, &full.get_validators_proof(&epoch).unwrap()).unwrap();
+        for block in &blocks[4..] {
+            light.insert(block.header.clone()).unwrap();
+        }
+        assert_eq!(light.get_status(), full.get_status());
+
+        let (header, _, proof) = full.get_transaction_proof(&tx.hash()).unwrap();
+        assert!(light.verify_transaction(&tx.hash(), &header.hash(), &proof));
+    }
+}
@@ -0,0 +1,246 @@
+//! Database schema versions and migrations.
+//!
+//! The schema version is stored in `COL_NODE_INFO`. Databases written before
+//! versioning have none and are at version 0, new ones start at
+//! `SCHEMA_VERSION`. On open, the migrations from the stored version up to
+//! `SCHEMA_VERSION` run in order, adding the columns they need.
+
+use kvdb::{Database, DatabaseConfig, KeyValueDB};
+use util::H256;
+use block::RichHeader;
+use db::{COL_EXTRA, COL_HEADERS, COL_NODE_INFO, NUM_COLUMNS};
+use error::Error;
+use rlp;
+
+/// Schema version of the databases written by this build.
+pub const SCHEMA_VERSION: u32 = 2;
+
+const VERSION_KEY: &'static [u8] = b"schema_version";
+
+/// Upgrade from the previous schema version.
+pub struct Migration {
+    /// Version after the migration.
+    pub version: u32,
+    /// Number of columns before the migration.
+    pub from_columns: u32,
+    /// Number of columns after the migration, missing ones are added first.
+    pub columns: u32,
+    pub description: &'static str,
+    pub migrate: fn(&Database) -> Result<(), String>,
+}
+
+fn no_op(_db: &Database) -> Result<(), String> {
+    Ok(())
+}
+
+fn decode<T: rlp::Decodable>(value: &[u8]) -> Result<T, String> {
+    rlp::UntrustedRlp::new(value).as_val().map_err(|e| format!("{:?}", e))
+}
+
+/// Blocks decoded from the db used to be identified by the hash of the
+/// signed header, and their children link to that hash. Keep it with the
+/// headers stored under it, `Header::hash` now leaves the block signature out.
+fn keep_block_hashes(db: &Database) -> Result<(), String> {
+    let db: &KeyValueDB = db;
+    let mut kept = 0;
+    let mut batch = db.transaction();
+    for (key, value) in db.iter(COL_HEADERS) {
+        let header: RichHeader = decode(&value)?;
+        let hash = H256::from_slice(&key);
+        if hash == header.hash() || hash != header.rlp_hash() {
+            continue;
+        }
+        header.hash.set(Some(hash));
+        batch.put(COL_HEADERS, &key, &rlp::encode(&header));
+        kept += 1;
+    }
+    info!("kept the signed header hash of {} blocks", kept);
+    db.write(batch)
+}
+
+/// Registered migrations, ordered by version.
+pub const MIGRATIONS: &'static [Migration] = &[
+    Migration {
+        version: 1,
+        from_columns: 8,
+        columns: 8,
+        description: "record the schema version",
+        migrate: no_op,
+    },
+    Migration {
+        version: 2,
+        from_columns: 8,
+        columns: 8,
+        description: "keep the hash of blocks stored under the signed header hash",
+        migrate: keep_block_hashes,
+    },
+];
+
+/// Stored schema version, `None` for databases written before versioning.
+pub fn version(db: &KeyValueDB) -> Result<Option<u32>, Error> {
+    match db.get(COL_NODE_INFO, VERSION_KEY).map_err(Error::Database)? {
+        Some(v) => rlp::UntrustedRlp::new(&v).as_val().map(Some).map_err(|_| Error::InvalidFormat),
+        None => Ok(None),
+    }
+}
+
+fn set_version(db: &KeyValueDB, version: u32) -> Result<(), Error> {
+    let mut batch = db.transaction();
+    batch.put(COL_NODE_INFO, VERSION_KEY, &rlp::encode(&version));
+    db.write(batch).map_err(Error::Database)
+}
+
+/// Open the database at `path` and bring it to `SCHEMA_VERSION`.
+///
+/// Fails when the database was written by a newer build or a migration fails.
+pub fn open(path: &str) -> Result<Database, Error> {
+    // older schemas may have fewer columns, and all of them must be opened.
+    let mut columns = vec![NUM_COLUMNS.unwrap()];
+    for m in MIGRATIONS.iter().rev() {
+        if !columns.contains(&m.from_columns) {
+            columns.push(m.from_columns);
+        }
+    }
+    let mut err = String::new();
+    for n in columns {
+        match Database::open(&DatabaseConfig::with_columns(Some(n)), path) {
+            Ok(db) => {
+                migrate(&db, MIGRATIONS, SCHEMA_VERSION)?;
+                return Ok(db);
+            }
+            Err(e) => err = e,
+        }
+    }
+    Err(Error::Database(err))
+}
+
+/// Run the migrations needed to bring `db` to version `target`.
+fn migrate(db: &Database, migrations: &[Migration], target: u32) -> Result<(), Error> {
+    let current = match version(db)? {
+        Some(v) => v,
+        // nothing to migrate in a new database.
+        None if db.get(COL_EXTRA, b"current_hash").map_err(Error::Database)?.is_none() => {
+            return set_version(db, target);
+        }
+        None => 0,
+    };
+    if current > target {
+        return Err(Error::SchemaTooNew(current));
+    }
+    for m in migrations.iter().filter(|m| m.version > current && m.version <= target) {
+        if db.num_columns() != m.from_columns {
+            let reason = format!("expected {} columns, found {}", m.from_columns, db.num_columns());
+            return Err(Error::MigrationFailed(m.version, reason));
+        }
+        info!("migrating database to version {}: {}", m.version, m.description);
+        while db.num_columns() < m.columns {
+            db.add_column().map_err(|e| Error::MigrationFailed(m.version, e))?;
+        }
+        (m.migrate)(db).map_err(|e| Error::MigrationFailed(m.version, e))?;
+        set_version(db, m.version)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devtools::RandomTempPath;
+    use block::Block;
+    use db::{Readable, Writable};
+    use transaction::SignedTransaction;
+
+    fn mark(db: &Database) -> Result<(), String> {
+        let mut batch = db.transaction();
+        batch.put(Some(8), b"migrated", b"yes");
+        db.write(batch)
+    }
+
+    #[test]
+    fn migrations_are_complete() {
+        let last = MIGRATIONS.last().unwrap();
+        assert_eq!(last.version, SCHEMA_VERSION);
+        assert_eq!(last.columns, NUM_COLUMNS.unwrap());
+        for (i, m) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(m.version, i as u32 + 1);
+        }
+    }
+
+    #[test]
+    fn new_and_unversioned_databases() {
+        let path = RandomTempPath::create_dir();
+        let db = open(path.as_str()).unwrap();
+        assert_eq!(version(&db).unwrap(), Some(SCHEMA_VERSION));
+
+        // written before versioning.
+        let mut batch = db.transaction();
+        batch.delete(COL_NODE_INFO, VERSION_KEY);
+        batch.put(COL_EXTRA, b"current_hash", &[0u8; 32]);
+        db.write(batch).unwrap();
+        assert_eq!(version(&db).unwrap(), None);
+        migrate(&db, MIGRATIONS, SCHEMA_VERSION).unwrap();
+        assert_eq!(version(&db).unwrap(), Some(SCHEMA_VERSION));
+
+        set_version(&db, SCHEMA_VERSION + 1).unwrap();
+        assert_eq!(migrate(&db, MIGRATIONS, SCHEMA_VERSION), Err(Error::SchemaTooNew(SCHEMA_VERSION + 1)));
+    }
+
+    #[test]
+    fn migration_adds_columns() {
+        let path = RandomTempPath::create_dir();
+        let db = Database::open(&DatabaseConfig::with_columns(NUM_COLUMNS), path.as_str()).unwrap();
+        set_version(&db, SCHEMA_VERSION).unwrap();
+        let migrations = [Migration {
+            version: SCHEMA_VERSION + 1,
+            from_columns: 8,
+            columns: 9,
+            description: "test",
+            migrate: mark,
+        }];
+        migrate(&db, &migrations, SCHEMA_VERSION + 1).unwrap();
+        assert_eq!(db.num_columns(), 9);
+        assert_eq!(version(&db).unwrap(), Some(SCHEMA_VERSION + 1));
+        assert_eq!(&*db.get(Some(8), b"migrated").unwrap().unwrap(), b"yes");
+
+        // already done.
+        migrate(&db, &migrations, SCHEMA_VERSION + 1).unwrap();
+        assert_eq!(db.num_columns(), 9);
+    }
+
+    #[test]
+    fn blocks_keep_the_signed_header_hash() {
+        let path = RandomTempPath::create_dir();
+        let db = open(path.as_str()).unwrap();
+        let key = H256::from(1);
+        let mut parent = Block::init(1, 1, H256::default(), vec![SignedTransaction::new(1)], vec![1]);
+        parent.sign(&key);
+        let old = parent.header.rlp_hash();
+        assert!(old != parent.hash());
+        // the child links to the signed hash its parent had when decoded.
+        let mut child = Block::init(2, 2, old, Vec::new(), vec![2]);
+        child.sign(&key);
+
+        // as stored by older builds.
+        let mut batch = db.transaction();
+        batch.put(COL_HEADERS, &old, &rlp::encode(&RichHeader { header: parent.header.clone(), verified: true }));
+        batch.write(COL_HEADERS, &child.hash(), &RichHeader { header: child.header.clone(), verified: true });
+        batch.write(COL_EXTRA, &1u64, &old);
+        batch.write(COL_EXTRA, &2u64, &child.hash());
+        db.write(batch).unwrap();
+
+        keep_block_hashes(&db).unwrap();
+        let child: RichHeader = db.read(COL_HEADERS, &child.hash()).unwrap();
+        let parent: RichHeader = db.read(COL_HEADERS, &child.parent_hash).unwrap();
+        assert_eq!(parent.hash(), old);
+        assert_eq!(db.read(COL_EXTRA, &1u64), Some(parent.hash()));
+        assert!(parent.sign_public().is_ok());
+        assert!(child.sign_public().is_ok());
+
+        // kept when written again.
+        let mut batch = db.transaction();
+        batch.write(COL_HEADERS, &old, &parent);
+        db.write(batch).unwrap();
+        let parent: RichHeader = db.read(COL_HEADERS, &old).unwrap();
+        assert_eq!(parent.hash(), old);
+    }
+}
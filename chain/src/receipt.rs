@@ -0,0 +1,50 @@
+use util::{H256, Hashable, HeapSizeOf, complete_merkle_root_raw};
+use rlp;
+
+/// Outcome of applying a transaction.
+#[derive(Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct Receipt {
+    /// Hash of the transaction.
+    pub transaction_hash: H256,
+    /// Whether the transaction changed the state.
+    pub success: bool,
+    /// State root after the transaction.
+    pub state_root: H256,
+    /// Nonce of the sender after the transaction, 0 if it failed.
+    pub nonce: u64,
+}
+
+impl Receipt {
+    pub fn hash(&self) -> H256 {
+        rlp::encode(self).sha3()
+    }
+}
+
+impl HeapSizeOf for Receipt {
+    fn heap_size_of_children(&self) -> usize {
+        0
+    }
+}
+
+/// Receipts of all transactions in a block.
+#[derive(Debug, Clone, PartialEq, Eq, Default, RlpEncodable, RlpDecodable)]
+pub struct BlockReceipts {
+    pub receipts: Vec<Receipt>,
+}
+
+impl BlockReceipts {
+    pub fn new(receipts: Vec<Receipt>) -> Self {
+        BlockReceipts { receipts: receipts }
+    }
+
+    ///calculate the receipts root
+    pub fn receipts_root(&self) -> H256 {
+        complete_merkle_root_raw(self.receipts.iter().map(|r| r.hash()).collect())
+    }
+}
+
+impl HeapSizeOf for BlockReceipts {
+    fn heap_size_of_children(&self) -> usize {
+        self.receipts.heap_size_of_children()
+    }
+}
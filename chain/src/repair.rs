@@ -0,0 +1,216 @@
+//! Consistency of the best block and the `COL_EXTRA` indexes.
+//!
+//! The best block hash is written in the same batch as the block number
+//! index, but data directories left by older builds or damaged disks may
+//! have them disagree. `check_head` runs at startup and moves the best block
+//! back to the last fully indexed ancestor. `rebuild_indexes` rewrites the
+//! block number and transaction address indexes from the headers.
+
+use std::collections::HashSet;
+use kvdb::{DBTransaction, KeyValueDB};
+use util::H256;
+use block::{BlockNumber, Body, RichHeader};
+use db::{self, Readable, Writable};
+use extras::{ExtrasIndex, TransactionAddress};
+use error::Error;
+
+/// Outcome of `rebuild_indexes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Best block after the repair.
+    pub head: (u64, H256),
+    /// Blocks dropped from the top of the stored best chain.
+    pub dropped: u64,
+    /// Canonical blocks and transactions indexed.
+    pub blocks: u64,
+    pub transactions: u64,
+}
+
+fn header(db: &KeyValueDB, hash: &H256) -> Option<RichHeader> {
+    db.read(db::COL_HEADERS, hash)
+}
+
+fn body(db: &KeyValueDB, hash: &H256) -> Option<Body> {
+    db.read(db::COL_BODIES, hash)
+}
+
+fn number(db: &KeyValueDB, height: BlockNumber) -> Option<H256> {
+    db.read(db::COL_EXTRA, &height)
+}
+
+fn stored_hash(db: &KeyValueDB, key: &[u8]) -> Option<H256> {
+    db.get(db::COL_EXTRA, key).expect("DB read failed.").map(|h| H256::from_slice(&h))
+}
+
+/// Whether the block is in the number index and its body is stored.
+fn is_indexed(db: &KeyValueDB, header: &RichHeader) -> bool {
+    let hash = header.hash();
+    number(db, header.height) == Some(hash) && body(db, &hash).is_some()
+}
+
+/// Highest block of the number index linked to genesis through indexed blocks.
+fn indexed_tip(db: &KeyValueDB) -> Option<(u64, H256)> {
+    let mut tip = (0, number(db, 0)?);
+    loop {
+        let next = match number(db, tip.0 + 1).and_then(|h| header(db, &h)) {
+            Some(h) => h,
+            None => return Some(tip),
+        };
+        if next.parent_hash != tip.1 || body(db, &next.hash()).is_none() {
+            return Some(tip);
+        }
+        tip = (next.height, next.hash());
+    }
+}
+
+/// Point the best block at `head`, drop the index entries above it and keep
+/// the final block on the best chain.
+///
+/// Returns whether anything was put in the batch.
+fn set_head(db: &KeyValueDB, batch: &mut DBTransaction, head: (u64, H256), finality_depth: u64) -> bool {
+    let mut changed = false;
+    if stored_hash(db, b"current_hash") != Some(head.1) {
+        batch.put(db::COL_EXTRA, b"current_hash", &head.1);
+        changed = true;
+    }
+    let mut height = head.0 + 1;
+    while number(db, height).is_some() {
+        Writable::delete::<H256, _>(batch, db::COL_EXTRA, &height);
+        height += 1;
+        changed = true;
+    }
+
+    let finalized = stored_hash(db, b"finalized_hash").and_then(|h| header(db, &h));
+    let on_chain = finalized.map_or(false, |f| f.height <= head.0 && number(db, f.height) == Some(f.hash()));
+    if !on_chain {
+        let height = head.0.saturating_sub(finality_depth);
+        if let Some(hash) = number(db, height) {
+            batch.put(db::COL_EXTRA, b"finalized_hash", &hash);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Check the best block and the `depth` blocks below it, and move the best
+/// block back to the last fully indexed ancestor if needed.
+///
+/// Returns the best block, `None` for a new database.
+pub fn check_head(db: &KeyValueDB, depth: u64, finality_depth: u64) -> Result<Option<(u64, H256)>, Error> {
+    let stored = match stored_hash(db, b"current_hash") {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+
+    // the highest indexed block such that the rest of the window is indexed too.
+    let mut candidate = None;
+    let mut current = header(db, &stored);
+    for _ in 0..(depth + 1) {
+        let h = match current {
+            Some(h) => h,
+            None => {
+                candidate = None;
+                break;
+            }
+        };
+        if !is_indexed(db, &h) {
+            candidate = None;
+        } else if candidate.is_none() {
+            candidate = Some((h.height, h.hash()));
+        }
+        if h.height == 0 {
+            break;
+        }
+        current = header(db, &h.parent_hash);
+    }
+
+    let head = match candidate {
+        Some(head) => head,
+        None => indexed_tip(db).ok_or(Error::UnknownAncestor)?,
+    };
+    if head.1 != stored {
+        warn!("best block {:?} is not fully indexed, fall back to {} {:?}", stored, head.0, head.1);
+    }
+    let mut batch = db.transaction();
+    if set_head(db, &mut batch, head, finality_depth) {
+        db.write(batch).map_err(Error::Database)?;
+    }
+    Ok(Some(head))
+}
+
+fn delete_prefixed(db: &KeyValueDB, batch: &mut DBTransaction, index: ExtrasIndex, len: usize) {
+    let prefix = [index as u8];
+    for (key, _) in db.iter_from_prefix(db::COL_EXTRA, &prefix) {
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        if key.len() == len {
+            batch.delete(db::COL_EXTRA, &key);
+        }
+    }
+}
+
+/// Rewrite the block number and transaction address indexes of `COL_EXTRA`
+/// from the headers and bodies, following parents from the stored best block.
+///
+/// Blocks above a missing body are dropped from the best chain, the
+/// highest indexed block is used if the stored best block is unknown.
+pub fn rebuild_indexes(db: &KeyValueDB, finality_depth: u64) -> Result<RepairReport, Error> {
+    let stored = stored_hash(db, b"current_hash");
+    let mut current = match stored.and_then(|h| header(db, &h)) {
+        Some(h) => h,
+        None => {
+            let tip = indexed_tip(db).ok_or(Error::UnknownAncestor)?;
+            warn!("best block {:?} is unknown, rebuild from {} {:?}", stored, tip.0, tip.1);
+            header(db, &tip.1).ok_or(Error::UnknownAncestor)?
+        }
+    };
+    let top = current.height;
+
+    // canonical blocks, best first.
+    let mut blocks: Vec<(u64, H256, Body)> = Vec::new();
+    loop {
+        let hash = current.hash();
+        match body(db, &hash) {
+            Some(b) => blocks.push((current.height, hash, b)),
+            None => blocks.clear(),
+        }
+        if current.height == 0 {
+            break;
+        }
+        current = header(db, &current.parent_hash).ok_or(Error::UnknownAncestor)?;
+    }
+    if blocks.is_empty() {
+        return Err(Error::UnknownAncestor);
+    }
+
+    // one batch, the deletes apply before the writes.
+    let mut batch = db.transaction();
+    delete_prefixed(db, &mut batch, ExtrasIndex::BlockHash, 5);
+    delete_prefixed(db, &mut batch, ExtrasIndex::TransactionAddress, 33);
+    let mut transactions = HashSet::new();
+    for &(height, hash, ref body) in blocks.iter().rev() {
+        batch.write(db::COL_EXTRA, &height, &hash);
+        for (i, tx) in body.transactions.iter().enumerate() {
+            batch.write(db::COL_EXTRA, &tx.hash(), &TransactionAddress { block_hash: hash, index: i });
+            transactions.insert(tx.hash());
+        }
+    }
+
+    let head = (blocks[0].0, blocks[0].1);
+    batch.put(db::COL_EXTRA, b"current_hash", &head.1);
+    let finalized = stored_hash(db, b"finalized_hash");
+    if !blocks.iter().any(|b| Some(b.1) == finalized) {
+        // blocks go down to genesis without gaps.
+        let hash = blocks[(finality_depth as usize).min(blocks.len() - 1)].1;
+        batch.put(db::COL_EXTRA, b"finalized_hash", &hash);
+    }
+    db.write(batch).map_err(Error::Database)?;
+
+    Ok(RepairReport {
+        head: head,
+        dropped: top - head.0,
+        blocks: blocks.len() as u64,
+        transactions: transactions.len() as u64,
+    })
+}
@@ -0,0 +1,410 @@
+//! Account state kept in an AVL trie over `COL_STATE`.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
+use util::{H256, Address, HashDB, DBValue, Hashable, Bytes, SHA3_NULL_RLP};
+use util::avl::{SecAVLDBMut, SecAVLDB, AVL, AVLMut, Recorder};
+use util::memorydb::MemoryDB;
+use crypto::pubkey_to_address;
+use kvdb::{DBTransaction, KeyValueDB};
+use transaction::SignedTransaction;
+use receipt::{Receipt, BlockReceipts};
+use validator::{ValidatorSet, ValidatorChange, PendingChanges};
+use equivocation::Equivocation;
+use error::Error;
+use db;
+use rlp::{self, NULL_RLP, Encodable, Decodable};
+
+const VALIDATORS_KEY: &'static [u8] = b"validators";
+const PENDING_CHANGES_KEY: &'static [u8] = b"pending_validator_changes";
+
+/// Account stored in the state trie.
+#[derive(Debug, Clone, PartialEq, Eq, Default, RlpEncodable, RlpDecodable)]
+pub struct Account {
+    /// Number of transactions sent from this account.
+    pub nonce: u64,
+    /// Data hash of the last transaction sent from this account.
+    pub last_data_hash: H256,
+}
+
+/// `HashDB` adapter over `COL_STATE`.
+///
+/// Changes are kept in an overlay until `commit` puts them into a batch.
+/// Trie nodes are never deleted from the column, so the state of any
+/// imported block stays readable by its root.
+pub struct StateDB {
+    db: Arc<KeyValueDB>,
+    overlay: HashMap<H256, (DBValue, i32)>,
+}
+
+impl StateDB {
+    pub fn new(db: Arc<KeyValueDB>) -> Self {
+        StateDB {
+            db: db,
+            overlay: HashMap::new(),
+        }
+    }
+
+    fn backing(&self, key: &H256) -> Option<DBValue> {
+        self.db.get(db::COL_STATE, key).expect("db get state failed")
+    }
+
+    /// Put all inserted nodes into the batch.
+    pub fn commit(&mut self, batch: &mut DBTransaction) {
+        for (key, (value, rc)) in self.overlay.drain() {
+            if rc > 0 {
+                batch.put(db::COL_STATE, &key, &value);
+            }
+        }
+    }
+}
+
+impl HashDB for StateDB {
+    fn keys(&self) -> HashMap<H256, i32> {
+        self.overlay.iter().map(|(k, v)| (*k, v.1)).collect()
+    }
+
+    fn get(&self, key: &H256) -> Option<DBValue> {
+        if key == &SHA3_NULL_RLP {
+            return Some(DBValue::from_slice(&NULL_RLP));
+        }
+        match self.overlay.get(key) {
+            Some(&(ref v, rc)) if rc > 0 => Some(v.clone()),
+            _ => self.backing(key),
+        }
+    }
+
+    fn contains(&self, key: &H256) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn insert(&mut self, value: &[u8]) -> H256 {
+        if value == &NULL_RLP {
+            return SHA3_NULL_RLP;
+        }
+        let key = value.sha3();
+        self.emplace(key, DBValue::from_slice(value));
+        key
+    }
+
+    fn emplace(&mut self, key: H256, value: DBValue) {
+        match self.overlay.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let &mut (ref mut old, ref mut rc) = entry.get_mut();
+                if *rc <= 0 {
+                    *old = value;
+                }
+                *rc += 1;
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((value, 1));
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &H256) {
+        // only the overlay is reference counted, old roots must stay readable.
+        if let Some(&mut (_, ref mut rc)) = self.overlay.get_mut(key) {
+            *rc -= 1;
+        }
+    }
+}
+
+/// Read the validator set of the state at `root` from the nodes of
+/// `State::validators_proof`, fails if they do not lead to the value.
+pub fn verify_validators_proof(root: &H256, proof: &[Bytes]) -> Result<Option<ValidatorSet>, Error> {
+    let mut db = MemoryDB::new();
+    for node in proof {
+        db.insert(node);
+    }
+    let avl = SecAVLDB::new(&db, root).map_err(|_| Error::InvalidStateRoot)?;
+    match avl.get(VALIDATORS_KEY) {
+        Ok(Some(v)) => rlp::UntrustedRlp::new(&v).as_val().map(Some).map_err(|_| Error::InvalidFormat),
+        Ok(None) => Ok(None),
+        Err(_) => Err(Error::InvalidStateRoot),
+    }
+}
+
+/// Account state at a given root.
+pub struct State {
+    db: StateDB,
+    root: H256,
+}
+
+impl State {
+    /// Open the state at `root`, fails if the root is not in the db.
+    pub fn from_existing(db: Arc<KeyValueDB>, root: H256) -> Result<Self, Error> {
+        let state = State {
+            db: StateDB::new(db),
+            root: root,
+        };
+        if !state.db.contains(&root) {
+            return Err(Error::InvalidStateRoot);
+        }
+        Ok(state)
+    }
+
+    pub fn root(&self) -> H256 {
+        self.root
+    }
+
+    fn get<T: Decodable>(&self, key: &[u8]) -> Option<T> {
+        let avl = SecAVLDB::new(&self.db, &self.root).ok()?;
+        match avl.get(key) {
+            Ok(Some(v)) => Some(rlp::decode(&v)),
+            _ => None,
+        }
+    }
+
+    fn put<T: Encodable>(&mut self, key: &[u8], value: &T) -> Result<(), Error> {
+        let mut root = self.root;
+        {
+            let mut avl = SecAVLDBMut::from_existing(&mut self.db, &mut root).map_err(|_| Error::InvalidStateRoot)?;
+            avl.insert(key, &rlp::encode(value)).map_err(|_| Error::InvalidStateRoot)?;
+        }
+        self.root = root;
+        Ok(())
+    }
+
+    /// Get the account with given address.
+    pub fn account(&self, address: &Address) -> Option<Account> {
+        self.get(address)
+    }
+
+    /// Get the active validator set, `None` if it was never initialized.
+    pub fn validators(&self) -> Option<ValidatorSet> {
+        self.get(VALIDATORS_KEY)
+    }
+
+    /// Trie nodes visited to read the validator set, for light clients.
+    pub fn validators_proof(&self) -> Vec<Bytes> {
+        let mut recorder = Recorder::new();
+        if let Ok(avl) = SecAVLDB::new(&self.db, &self.root) {
+            let _ = avl.get_with(VALIDATORS_KEY, &mut recorder);
+        }
+        recorder.drain().into_iter().map(|r| r.data).collect()
+    }
+
+    /// Get the validator changes waiting for the next epoch boundary.
+    pub fn pending_changes(&self) -> PendingChanges {
+        self.get(PENDING_CHANGES_KEY).unwrap_or_default()
+    }
+
+    /// Check evidence against the active set and the queued changes, it is
+    /// rejected once the offender is removed or its removal is queued.
+    pub fn check_evidence(&self, evidence: &Equivocation) -> Result<(), Error> {
+        evidence.verify(&self.validators().unwrap_or_default())?;
+        if self.pending_changes().changes.iter().any(|c| c.validator.signer_public_key == evidence.signer) {
+            return Err(Error::InvalidEvidence);
+        }
+        Ok(())
+    }
+
+    /// Set the validator set, used for the genesis state.
+    pub fn init_validators(&mut self, validators: &ValidatorSet) -> Result<(), Error> {
+        self.put(VALIDATORS_KEY, validators)
+    }
+
+    /// Make the pending validator changes active, called at epoch boundaries.
+    pub fn promote_validators(&mut self) -> Result<(), Error> {
+        let pending = self.pending_changes();
+        if pending.changes.is_empty() {
+            return Ok(());
+        }
+        let mut validators = self.validators().unwrap_or_default();
+        for change in &pending.changes {
+            validators.apply(change);
+        }
+        self.put(VALIDATORS_KEY, &validators)?;
+        self.put(PENDING_CHANGES_KEY, &PendingChanges::default())
+    }
+
+    /// Queue a change, at most one per validator and epoch.
+    fn queue_change(&mut self, change: ValidatorChange, err: Error) -> Result<(), Error> {
+        let mut pending = self.pending_changes();
+        let signer = change.validator.signer_public_key;
+        if pending.changes.iter().any(|c| c.validator.signer_public_key == signer) {
+            return Err(err);
+        }
+        pending.changes.push(change);
+        self.put(PENDING_CHANGES_KEY, &pending)
+    }
+
+    /// Apply a transaction, the sender's account is bumped.
+    ///
+    /// A validator change is checked against the active set and queued.
+    /// Equivocation evidence queues the removal of the offender.
+    pub fn apply_transaction(&mut self, tx: &SignedTransaction) -> Result<u64, Error> {
+        let sender = pubkey_to_address(&tx.recover_public()?);
+        if let Some(change) = ValidatorChange::from_data(&tx.data) {
+            let change = change?;
+            change.verify(&self.validators().unwrap_or_default(), tx.timestamp)?;
+            self.queue_change(change, Error::InvalidValidatorChange)?;
+        } else if let Some(evidence) = Equivocation::from_data(&tx.data) {
+            let evidence = evidence?;
+            self.check_evidence(&evidence)?;
+            let validators = self.validators().unwrap_or_default();
+            let offender = validators.get(&evidence.signer).expect("verified evidence signer is a validator").clone();
+            self.queue_change(ValidatorChange::new(false, offender, tx.timestamp), Error::InvalidEvidence)?;
+        }
+
+        let mut account = self.account(&sender).unwrap_or_default();
+        account.nonce += 1;
+        account.last_data_hash = tx.data.sha3();
+        self.put(&sender, &account)?;
+        Ok(account.nonce)
+    }
+
+    /// Apply transactions of a block, the ones without a valid sender fail without changes.
+    pub fn apply_transactions(&mut self, txs: &[SignedTransaction]) -> BlockReceipts {
+        let receipts = txs.iter().map(|tx| {
+            let (success, nonce) = match self.apply_transaction(tx) {
+                Ok(nonce) => (true, nonce),
+                Err(e) => {
+                    trace!("transaction {:?} failed: {:?}", tx.hash(), e);
+                    (false, 0)
+                }
+            };
+            Receipt {
+                transaction_hash: tx.hash(),
+                success: success,
+                state_root: self.root,
+                nonce: nonce,
+            }
+        }).collect();
+        BlockReceipts::new(receipts)
+    }
+
+    /// Put the changed trie nodes into the batch.
+    pub fn commit(&mut self, batch: &mut DBTransaction) {
+        self.db.commit(batch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvdb::in_memory;
+    use crypto::{sign, KeyPair};
+    use validator::Validator;
+    use block::Block;
+
+    fn signed_tx(t: u64, key: &H256) -> SignedTransaction {
+        let mut tx = SignedTransaction::new(t);
+        tx.signature = sign(key, &tx.hash()).unwrap().into();
+        tx
+    }
+
+    #[test]
+    fn old_roots_stay_readable() {
+        let db: Arc<KeyValueDB> = Arc::new(in_memory(db::NUM_COLUMNS.unwrap()));
+        let key = H256::from("40f2d8f8e1594579824fd04edfc7ff1ddffd6be153b23f4318e1acff037d3ea9");
+        let address = KeyPair::from_privkey(key).unwrap().address();
+
+        let mut state = State::from_existing(db.clone(), SHA3_NULL_RLP).unwrap();
+        let receipts = state.apply_transactions(&[signed_tx(1, &key), SignedTransaction::new(2)]);
+        assert_eq!(receipts.receipts.len(), 2);
+        assert!(receipts.receipts[0].success);
+        assert!(!receipts.receipts[1].success);
+        assert_eq!(receipts.receipts[1].state_root, state.root());
+        let mut batch = db.transaction();
+        state.commit(&mut batch);
+        db.write(batch).unwrap();
+        let first = state.root();
+        assert!(first != SHA3_NULL_RLP);
+
+        let mut state = State::from_existing(db.clone(), first).unwrap();
+        assert_eq!(state.apply_transaction(&signed_tx(3, &key)), Ok(2));
+        let mut batch = db.transaction();
+        state.commit(&mut batch);
+        db.write(batch).unwrap();
+
+        let state = State::from_existing(db.clone(), state.root()).unwrap();
+        assert_eq!(state.account(&address).unwrap().nonce, 2);
+        let state = State::from_existing(db.clone(), first).unwrap();
+        assert_eq!(state.account(&address).unwrap().nonce, 1);
+        assert!(State::from_existing(db, H256::from(1)).is_err());
+    }
+
+    #[test]
+    fn validator_changes_wait_for_promotion() {
+        let db: Arc<KeyValueDB> = Arc::new(in_memory(db::NUM_COLUMNS.unwrap()));
+        let keys: Vec<H256> = (1..3).map(|i| H256::from(i as u64)).collect();
+        let validators: Vec<Validator> = keys.iter().map(|k| Validator {
+            proof_public_key: Vec::new(),
+            proof_public_g: Vec::new(),
+            signer_public_key: *KeyPair::from_privkey(*k).unwrap().pubkey(),
+        }).collect();
+
+        let mut state = State::from_existing(db, SHA3_NULL_RLP).unwrap();
+        assert_eq!(state.validators(), None);
+        state.init_validators(&ValidatorSet::new(vec![validators[0].clone()])).unwrap();
+
+        let mut change = ValidatorChange::new(true, validators[1].clone(), 5);
+        let mut tx = signed_tx(5, &keys[1]);
+        tx.set_data(change.to_data());
+        tx.signature = sign(&keys[1], &tx.hash()).unwrap().into();
+        assert_eq!(state.apply_transaction(&tx), Err(Error::InvalidValidatorChange));
+
+        change.sign(&keys[0]);
+        tx.set_data(change.to_data());
+        tx.signature = sign(&keys[1], &tx.hash()).unwrap().into();
+        assert_eq!(state.apply_transaction(&tx), Ok(1));
+        assert_eq!(state.apply_transaction(&tx), Err(Error::InvalidValidatorChange));
+        assert_eq!(state.validators().unwrap().len(), 1);
+
+        state.promote_validators().unwrap();
+        assert_eq!(state.validators().unwrap().len(), 2);
+        assert!(state.pending_changes().changes.is_empty());
+    }
+
+    #[test]
+    fn validators_are_proven_against_the_root() {
+        let db: Arc<KeyValueDB> = Arc::new(in_memory(db::NUM_COLUMNS.unwrap()));
+        let mut state = State::from_existing(db, SHA3_NULL_RLP).unwrap();
+        assert_eq!(verify_validators_proof(&state.root(), &state.validators_proof()), Ok(None));
+
+        let set = ValidatorSet::new(vec![Validator {
+            proof_public_key: vec![1],
+            proof_public_g: vec![2],
+            signer_public_key: *KeyPair::from_privkey(H256::from(1)).unwrap().pubkey(),
+        }]);
+        state.init_validators(&set).unwrap();
+        state.apply_transaction(&signed_tx(1, &H256::from(2))).unwrap();
+        let proof = state.validators_proof();
+        assert_eq!(verify_validators_proof(&state.root(), &proof), Ok(Some(set)));
+        assert!(verify_validators_proof(&state.root(), &proof[..1]).is_err());
+        assert!(verify_validators_proof(&H256::from(1), &proof).is_err());
+    }
+
+    #[test]
+    fn evidence_removes_offender() {
+        let db: Arc<KeyValueDB> = Arc::new(in_memory(db::NUM_COLUMNS.unwrap()));
+        let key = H256::from(1);
+        let signer = *KeyPair::from_privkey(key).unwrap().pubkey();
+        let mut state = State::from_existing(db, SHA3_NULL_RLP).unwrap();
+        state.init_validators(&ValidatorSet::new(vec![Validator {
+            proof_public_key: Vec::new(),
+            proof_public_g: Vec::new(),
+            signer_public_key: signer,
+        }])).unwrap();
+
+        let mut a = Block::init(1, 7, H256::default(), Vec::new(), vec![1]);
+        let mut b = Block::init(1, 7, H256::default(), Vec::new(), vec![2]);
+        a.sign(&key);
+        b.sign(&key);
+        let reporter = H256::from(2);
+        let forged = Equivocation::new(signer, a.header.clone(), a.header.clone());
+        assert_eq!(state.apply_transaction(&forged.to_transaction(8, &reporter)), Err(Error::InvalidEvidence));
+
+        let evidence = Equivocation::new(signer, a.header, b.header);
+        assert_eq!(state.apply_transaction(&evidence.to_transaction(8, &reporter)), Ok(1));
+        assert_eq!(state.apply_transaction(&evidence.to_transaction(9, &reporter)), Err(Error::InvalidEvidence));
+        assert!(state.validators().unwrap().contains(&signer));
+
+        state.promote_validators().unwrap();
+        assert!(state.validators().unwrap().is_empty());
+        assert_eq!(state.apply_transaction(&evidence.to_transaction(10, &reporter)), Err(Error::InvalidEvidence));
+    }
+}
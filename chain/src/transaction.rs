@@ -84,6 +84,12 @@ impl SignedTransaction {
         self.hash
     }
 
+    ///set data and refresh the hash, the transaction must be signed again
+    pub fn set_data(&mut self, data: Vec<u8>) {
+        self.transaction.set_data(data);
+        self.hash = self.transaction.cal_hash();
+    }
+
     ///the hash of the transaction
     pub fn timestamp(&self) -> u64 {
         self.timestamp
@@ -0,0 +1,219 @@
+//! Validator set and the transactions that change it.
+//!
+//! A change is carried in the data of a regular transaction, prefixed by
+//! `VALIDATOR_CHANGE_PREFIX`. It must be signed by a strict majority of the
+//! active validators, is queued when its transaction is applied, and takes
+//! effect at the next `epoch_len` boundary.
+
+use std::collections::HashSet;
+use util::{H256, H512, H520, Hashable};
+use util::config::KeyGroup;
+use crypto::{recover, sign, Signature};
+use error::Error;
+use rlp::{self, RlpStream};
+
+/// Prefix of the transaction data carrying a validator change.
+pub const VALIDATOR_CHANGE_PREFIX: &'static [u8] = b"sleepy/validator-change";
+
+/// Height of the block whose state holds the validator set of blocks at
+/// `height`: the last block of the previous epoch, or genesis.
+///
+/// The set only changes when the last block of an epoch is executed.
+pub fn validators_height(height: u64, epoch_len: u64) -> u64 {
+    if epoch_len == 0 {
+        return 0;
+    }
+    (height - height % epoch_len).saturating_sub(1)
+}
+
+/// Keys of a miner.
+#[derive(Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct Validator {
+    pub proof_public_key: Vec<u8>,
+    pub proof_public_g: Vec<u8>,
+    pub signer_public_key: H512,
+}
+
+impl From<KeyGroup> for Validator {
+    fn from(k: KeyGroup) -> Self {
+        Validator {
+            proof_public_key: k.proof_public_key,
+            proof_public_g: k.proof_public_g,
+            signer_public_key: k.signer_public_key,
+        }
+    }
+}
+
+/// Miners allowed to propose blocks.
+#[derive(Debug, Clone, PartialEq, Eq, Default, RlpEncodable, RlpDecodable)]
+pub struct ValidatorSet {
+    pub validators: Vec<Validator>,
+}
+
+impl ValidatorSet {
+    pub fn new(validators: Vec<Validator>) -> Self {
+        ValidatorSet { validators: validators }
+    }
+
+    pub fn get(&self, signer: &H512) -> Option<&Validator> {
+        self.validators.iter().find(|v| &v.signer_public_key == signer)
+    }
+
+    pub fn contains(&self, signer: &H512) -> bool {
+        self.get(signer).is_some()
+    }
+
+    /// Get the proof public key and g of given signer.
+    pub fn proof_keys(&self, signer: &H512) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.get(signer).map(|v| (v.proof_public_key.clone(), v.proof_public_g.clone()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.validators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    /// Apply a change, it must have been verified against this set.
+    pub fn apply(&mut self, change: &ValidatorChange) {
+        let signer = change.validator.signer_public_key;
+        self.validators.retain(|v| v.signer_public_key != signer);
+        if change.add {
+            self.validators.push(change.validator.clone());
+        }
+    }
+}
+
+impl From<Vec<KeyGroup>> for ValidatorSet {
+    fn from(keygroups: Vec<KeyGroup>) -> Self {
+        ValidatorSet::new(keygroups.into_iter().map(Validator::from).collect())
+    }
+}
+
+/// Proposal to add or remove a validator.
+#[derive(Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct ValidatorChange {
+    /// Add the validator if true, remove it otherwise.
+    pub add: bool,
+    pub validator: Validator,
+    /// Must match the carrying transaction's timestamp, so a change can't be replayed.
+    pub timestamp: u64,
+    /// Signatures of the proposal hash by active validators.
+    pub signatures: Vec<H520>,
+}
+
+/// Changes waiting for the next epoch boundary.
+#[derive(Debug, Clone, PartialEq, Eq, Default, RlpEncodable, RlpDecodable)]
+pub struct PendingChanges {
+    pub changes: Vec<ValidatorChange>,
+}
+
+impl ValidatorChange {
+    pub fn new(add: bool, validator: Validator, timestamp: u64) -> Self {
+        ValidatorChange {
+            add: add,
+            validator: validator,
+            timestamp: timestamp,
+            signatures: Vec::new(),
+        }
+    }
+
+    /// Hash signed by the validators, signatures excluded.
+    pub fn proposal_hash(&self) -> H256 {
+        let mut s = RlpStream::new_list(3);
+        s.append(&self.add);
+        s.append(&self.validator);
+        s.append(&self.timestamp);
+        s.out().sha3()
+    }
+
+    /// Add the signature of a validator.
+    pub fn sign(&mut self, privkey: &H256) {
+        let sig = sign(privkey, &self.proposal_hash()).expect("failed to sign validator change");
+        self.signatures.push(sig.into());
+    }
+
+    /// Encode into transaction data.
+    pub fn to_data(&self) -> Vec<u8> {
+        let mut data = VALIDATOR_CHANGE_PREFIX.to_vec();
+        data.extend_from_slice(&rlp::encode(self));
+        data
+    }
+
+    /// Decode from transaction data, `None` if the data is not a validator change.
+    pub fn from_data(data: &[u8]) -> Option<Result<Self, Error>> {
+        if !data.starts_with(VALIDATOR_CHANGE_PREFIX) {
+            return None;
+        }
+        let raw = &data[VALIDATOR_CHANGE_PREFIX.len()..];
+        Some(rlp::UntrustedRlp::new(raw).as_val().map_err(|_| Error::InvalidFormat))
+    }
+
+    /// Check the change against the active set and the carrying transaction's timestamp.
+    pub fn verify(&self, set: &ValidatorSet, timestamp: u64) -> Result<(), Error> {
+        if self.timestamp != timestamp {
+            return Err(Error::InvalidValidatorChange);
+        }
+        if self.add == set.contains(&self.validator.signer_public_key) {
+            return Err(Error::InvalidValidatorChange);
+        }
+
+        let hash = self.proposal_hash();
+        let mut voters = HashSet::new();
+        for sig in &self.signatures {
+            let sig: Signature = (*sig).into();
+            let public = recover(&sig, &hash).map_err(|_| Error::InvalidSignature)?;
+            if set.contains(&public) {
+                voters.insert(public);
+            }
+        }
+
+        if voters.len() * 2 <= set.len() {
+            return Err(Error::InvalidValidatorChange);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::KeyPair;
+
+    fn validator(privkey: &H256) -> Validator {
+        Validator {
+            proof_public_key: vec![1],
+            proof_public_g: vec![2],
+            signer_public_key: *KeyPair::from_privkey(*privkey).unwrap().pubkey(),
+        }
+    }
+
+    #[test]
+    fn change_needs_majority() {
+        let keys: Vec<H256> = (1..5).map(|i| H256::from(i as u64)).collect();
+        let set = ValidatorSet::new(keys[..3].iter().map(validator).collect());
+
+        let mut change = ValidatorChange::new(true, validator(&keys[3]), 10);
+        change.sign(&keys[0]);
+        change.sign(&keys[0]);
+        assert_eq!(change.verify(&set, 10), Err(Error::InvalidValidatorChange));
+
+        change.sign(&keys[3]);
+        assert_eq!(change.verify(&set, 10), Err(Error::InvalidValidatorChange));
+
+        change.sign(&keys[1]);
+        assert_eq!(change.verify(&set, 10), Ok(()));
+        assert_eq!(change.verify(&set, 11), Err(Error::InvalidValidatorChange));
+
+        let decoded = ValidatorChange::from_data(&change.to_data()).unwrap().unwrap();
+        assert_eq!(decoded, change);
+        assert!(ValidatorChange::from_data(b"hello").is_none());
+
+        let mut set = set;
+        set.apply(&change);
+        assert_eq!(set.len(), 4);
+        assert_eq!(change.verify(&set, 10), Err(Error::InvalidValidatorChange));
+    }
+}
@@ -0,0 +1,124 @@
+//! Re-verification of the stored best chain.
+//!
+//! Canonical blocks are checked again from genesis without trusting their
+//! `verified` flag: hash, parent link and timestamp, lottery proof and block
+//! signature, transaction hashes and duplicates, and the transactions, state
+//! and receipts roots.
+
+use std::collections::{HashSet, VecDeque};
+use util::H256;
+use util::config::SleepyConfig;
+use block::Block;
+use chain::{tx_left_bound, Chain, TX_WINDOW};
+use validator::ValidatorSet;
+use error::Error;
+
+/// Outcome of `verify_chain`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Blocks and transactions checked, genesis included.
+    pub blocks: u64,
+    pub transactions: u64,
+    /// Checked blocks stored without the verified flag.
+    pub unverified: u64,
+    /// Height, hash and error of the first invalid block.
+    pub invalid: Option<(u64, H256, Error)>,
+}
+
+/// Timestamp and transactions of the blocks in the duplicate window.
+struct Window {
+    blocks: VecDeque<(u64, HashSet<H256>)>,
+}
+
+impl Window {
+    fn check(&self, block: &Block, nps: u64) -> Result<(), Error> {
+        let bound = self.blocks.front().map_or(0, |b| tx_left_bound(b.0, nps));
+        let mut seen = HashSet::new();
+        for tx in &block.body.transactions {
+            if tx.timestamp <= bound {
+                return Err(Error::OverdueTransaction);
+            }
+            if !seen.insert(tx.hash()) || self.blocks.iter().any(|b| b.1.contains(&tx.hash())) {
+                return Err(Error::DuplicateTransaction);
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, block: &Block) {
+        if self.blocks.len() as u64 > TX_WINDOW {
+            self.blocks.pop_front();
+        }
+        let hashes = block.body.transactions.iter().map(|t| t.hash()).collect();
+        self.blocks.push_back((block.timestamp, hashes));
+    }
+}
+
+fn check_block(chain: &Chain, config: &SleepyConfig, hash: &H256, block: &Block, parent: &Block) -> Result<(), Error> {
+    // the hash of a decoded header is recomputed, or checked for the ones
+    // stored by older builds.
+    if block.hash() != *hash || block.parent_hash != parent.hash() || block.height != parent.height + 1 {
+        return Err(Error::InvalidFormat);
+    }
+    if block.timestamp <= parent.timestamp {
+        return Err(Error::InvalidTimestamp);
+    }
+    block.check_transactions_root()?;
+
+    let seed = chain.anc_hash(parent.height, parent.hash()).ok_or(Error::UnknownAncestor)?;
+    let validators = chain.validators_at(&parent.hash())
+        .unwrap_or_else(|| ValidatorSet::from(config.get_keygroups().clone()));
+    chain.check_header_proof(&block.header, seed, &validators)?;
+
+    chain.check_state(block)?;
+    Ok(())
+}
+
+/// Check the canonical blocks from genesis to the best one, stops at the
+/// first invalid block.
+pub fn verify_chain(chain: &Chain, config: &SleepyConfig) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    let best = chain.current_height();
+    let genesis = match chain.block_hash_by_number(0).and_then(|h| chain.get_block_by_hash(&h)) {
+        Some(b) => b,
+        None => {
+            report.invalid = Some((0, H256::default(), Error::UnknownAncestor));
+            return report;
+        }
+    };
+    let mut window = Window { blocks: VecDeque::new() };
+    window.push(&genesis);
+    report.blocks = 1;
+
+    let mut parent = genesis;
+    for height in 1..(best + 1) {
+        let hash = chain.block_hash_by_number(height).unwrap_or_default();
+        let (header, body) = match (chain.get_block_header_by_hash(&hash), chain.get_block_body_by_hash(&hash)) {
+            (Some(h), Some(b)) => (h, b),
+            _ => {
+                report.invalid = Some((height, hash, Error::UnknownAncestor));
+                return report;
+            }
+        };
+        if !header.verified {
+            report.unverified += 1;
+        }
+        let block = Block { header: header.header, body: body };
+
+        let ret = check_block(chain, config, &hash, &block, &parent)
+            .and_then(|_| window.check(&block, config.nps));
+        if let Err(e) = ret {
+            report.invalid = Some((height, hash, e));
+            return report;
+        }
+
+        report.blocks += 1;
+        report.transactions += block.body.transactions.len() as u64;
+        if height % 1000 == 0 {
+            info!("verified {} of {} blocks", height, best);
+        }
+        window.push(&block);
+        parent = block;
+    }
+    report
+}
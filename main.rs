@@ -12,28 +12,45 @@ extern crate miner;
 extern crate parking_lot;
 extern crate tx_pool;
 extern crate kvdb;
+extern crate rpc;
 
 use env_logger::LogBuilder;
 use std::env;
 use log::{LogLevelFilter, LogRecord};
-use util::config::SleepyConfig;
+use util::config::{Behaviour, SleepyConfig};
 use network::server::start_server;
 use network::connection::{start_client, Operation};
 use network::msgclass::MsgClass;
-use std::sync::mpsc::channel;
-use clap::App;
+use network::sync::{Synchronizer, MAX_HEADERS};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use clap::{App, ArgMatches, SubCommand};
 use std::time::Duration;
 use std::thread;
 use bincode::{serialize, deserialize, Infinite};
 use miner::start_miner;
+use miner::strategy;
+use miner::handler::Handler;
 use chain::chain::Chain;
+use chain::light::LightChain;
+use chain::block::Header;
+use chain::export::{export_blocks, import_blocks};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::process;
 use chain::error::Error;
 use std::sync::Arc;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use tx_pool::Pool;
 use util::datapath::DataPath;
-use kvdb::{Database, DatabaseConfig};
-use chain::db;
+use util::hash::H256;
+use kvdb::Database;
+use rpc::{start_rpc, start_ws, RpcContext};
+use chain::migration;
+use chain::repair;
+use chain::verify::verify_chain;
+
+/// Seconds between two status broadcasts.
+const STATUS_INTERVAL: u64 = 30;
 
 pub fn log_init() {
     let format = |record: &LogRecord| {
@@ -66,7 +83,21 @@ fn main() {
         .version("0.1")
         .author("Cryptape")
         .about("Sleepy Node powered by Rust")
-        .args_from_usage("-c, --config=[FILE] 'Sets a custom config file'")
+        .args_from_usage("-c, --config=[FILE] 'Sets a custom config file'
+                          -l, --light 'Follow headers only'
+                          --watch=[HASH]... 'Transactions to prove in light mode'
+                          --repair 'Rebuild the block number and transaction indexes before starting'")
+        .subcommand(SubCommand::with_name("export")
+                        .about("Write canonical blocks to a file")
+                        .args_from_usage("--from=[HEIGHT] 'First block, 0 by default'
+                                          --to=[HEIGHT] 'Last block, the best one by default'
+                                          <FILE> 'Output file'"))
+        .subcommand(SubCommand::with_name("import")
+                        .about("Import blocks from a file")
+                        .args_from_usage("--no-proof-check 'Trust the file, skip proof checks'
+                                          <FILE> 'Input file'"))
+        .subcommand(SubCommand::with_name("verify-chain")
+                        .about("Check the stored best chain again from genesis"))
         .get_matches();
 
     let mut config_path = "config";
@@ -80,8 +111,42 @@ fn main() {
 
     let nosql_path = DataPath::nosql_path();
     trace!("nosql_path is {:?}", nosql_path);
-    let db_config = DatabaseConfig::with_columns(db::NUM_COLUMNS);
-    let db = Database::open(&db_config, &nosql_path).unwrap();
+    let db = match migration::open(&nosql_path) {
+        Ok(db) => db,
+        Err(Error::SchemaTooNew(version)) => {
+            error!("database schema version {} is newer than the supported {}, upgrade sleepy",
+                   version, migration::SCHEMA_VERSION);
+            process::exit(1);
+        }
+        Err(e) => {
+            error!("open database {} failed {:?}", nosql_path, e);
+            process::exit(1);
+        }
+    };
+
+    if matches.is_present("repair") {
+        match repair::rebuild_indexes(&db, config.finality_depth) {
+            Ok(report) => info!("rebuilt indexes of {} blocks and {} transactions, best block {:?}, {} blocks dropped",
+                                report.blocks, report.transactions, report.head, report.dropped),
+            Err(e) => {
+                error!("repair failed {:?}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(sub) = matches.subcommand_matches("export") {
+        run_export(config, db, sub);
+        return;
+    }
+    if let Some(sub) = matches.subcommand_matches("import") {
+        run_import(config, db, sub);
+        return;
+    }
+    if matches.subcommand_matches("verify-chain").is_some() {
+        run_verify(config, db);
+        return;
+    }
 
     let (stx, srx) = channel();
 
@@ -94,7 +159,8 @@ fn main() {
 
     // connect peers
     let (ctx, crx) = channel();
-    start_client(&config, crx);
+    let (connected_tx, connected_rx) = channel();
+    start_client(&config, crx, connected_tx);
 
     //make sure connect to other peers
     thread::sleep(Duration::new(20, 0));
@@ -102,74 +168,226 @@ fn main() {
     let config = Arc::new(RwLock::new(config));
     let db = Arc::new(db);
 
+    if matches.is_present("light") {
+        let watch = matches.values_of("watch").map_or(Vec::new(), |hashes| {
+            hashes.map(|h| h.trim_left_matches("0x").parse().expect("invalid --watch")).collect()
+        });
+        run_light(config, db, srx, ctx, watch);
+        return;
+    }
+
     // init chain
-    let chain = Chain::init(config.clone(), db);
+    let chain = init_chain(config.clone(), db);
 
     // init tx pool
     let tx_pool = Pool::new(1000, 300);
     let tx_pool = Arc::new(RwLock::new(tx_pool));
 
+    // honest unless configured otherwise, shared by the miner and the message loop
+    let strategy = {
+        let config = config.read();
+        if config.byzantine.behaviour != Behaviour::Honest {
+            warn!("byzantine node, behaviour {:?}", config.byzantine.behaviour);
+        }
+        Arc::new(Mutex::new(strategy::from_config(&config.byzantine, config.get_signer_private_key())))
+    };
+
     // start miner
-    start_miner(ctx.clone(), chain.clone(), config.clone(), tx_pool.clone());
+    let mined = start_miner(ctx.clone(), chain.clone(), config.clone(), tx_pool.clone(), chain.clock(), strategy.clone());
     
-    //garbage collect
+    // header-first sync
+    let sync = Arc::new(Mutex::new(Synchronizer::new(chain.clone(), ctx.clone())));
+    let sync1 = sync.clone();
+    thread::spawn(move || loop {
+                      thread::sleep(Duration::from_millis(1000));
+                      sync1.lock().tick();
+                  });
+
+    // send our status to new connections and every STATUS_INTERVAL
+    let sync2 = sync.clone();
+    thread::spawn(move || for peer in connected_rx {
+                      sync2.lock().send_status(peer);
+                  });
+    let sync3 = sync.clone();
+    let ctx1 = ctx.clone();
+    thread::spawn(move || loop {
+                      thread::sleep(Duration::from_secs(STATUS_INTERVAL));
+                      let status = { sync3.lock().status() };
+                      let message = serialize(&MsgClass::STATUS(status), Infinite).unwrap();
+                      ctx1.send((0, Operation::BROADCAST, message)).unwrap();
+                  });
+
+    // json-rpc, stopped when dropped
+    let _rpc = start_rpc(&config.read(), RpcContext {
+        chain: chain.clone(),
+        tx_pool: tx_pool.clone(),
+        sync: sync.clone(),
+        sender: ctx.clone(),
+    });
+
+    // websocket push, the miner and pool feeds are dropped when disabled
+    let pending = { tx_pool.write().subscribe() };
+    let _ws = start_ws(&config.read(), chain.clone(), pending, mined);
+
+    //garbage collect and report the block buffers
     let chain1 = chain.clone();
     thread::spawn(move || loop {
                       thread::sleep(Duration::from_millis(100000));
                       chain1.collect_garbage();
+                      info!("orphan buffer {:?}", chain1.orphan_stats());
+                      info!("future buffer {:?}", chain1.future_stats());
                   });
 
+    let handler = Handler::new(chain, tx_pool, sync, strategy, ctx);
+    loop {
+        let (origin, msg) = srx.recv().unwrap();
+        handler.handle(origin, &msg);
+    }
+}
+
+/// Follow the headers announced by peers, without blocks nor state.
+///
+/// Once caught up with a peer, ask it for the inclusion proofs of the
+/// `watch` transactions until each is proven on our best chain.
+fn run_light(config: Arc<RwLock<SleepyConfig>>,
+             db: Arc<Database>,
+             srx: Receiver<(u32, Vec<u8>)>,
+             ctx: Sender<(u32, Operation, Vec<u8>)>,
+             mut watch: Vec<H256>) {
+    let chain = LightChain::init(config, db);
+    let genesis = chain.block_hash_by_number(0).expect("genesis not found!");
+    let send = |origin: u32, msg: MsgClass| {
+        let message = serialize(&msg, Infinite).unwrap();
+        ctx.send((origin, Operation::SINGLE, message)).unwrap();
+    };
+    let request_headers = |origin: u32| {
+        let (height, _) = chain.get_status();
+        send(origin, MsgClass::GETHEADERS { from_height: height + 1, count: MAX_HEADERS });
+    };
+    let request_proofs = |origin: u32, watch: &[H256]| for hash in watch {
+        send(origin, MsgClass::GETTXPROOF(*hash));
+    };
+    // the header waits for the validator set of its epoch.
+    let request_validators = |origin: u32, header: &Header| if let Some(hash) = chain.epoch_hash(header) {
+        send(origin, MsgClass::GETVALIDATORS(hash));
+    };
+
+    info!("Sleepy light node start...");
     loop {
         let (origin, msg) = srx.recv().unwrap();
-        trace!("get msg from {}", origin);
-        let decoded: MsgClass = deserialize(&msg[..]).unwrap();
+        let decoded: MsgClass = match deserialize(&msg[..]) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("undecodable message from {}: {:?}", origin, e);
+                continue;
+            }
+        };
         match decoded {
             MsgClass::BLOCK(blk) => {
-                trace!("get block {} from {}", blk.height, origin);
-                let ret = chain.insert(blk.clone());
-                match ret {
-                    Ok(_) => {}
-                    Err(err) => {
-                        if err != Error::DuplicateBlock {
-                            warn!("insert block error {:?}", err);
+                match chain.insert(blk.header.clone()) {
+                    Err(Error::UnknownParent) => request_headers(origin),
+                    Err(Error::UnknownValidators) => request_validators(origin, &blk.header),
+                    _ => {}
+                }
+            }
+            MsgClass::HEADERS(headers) => {
+                let mut more = headers.len() as u64 == MAX_HEADERS;
+                for header in headers {
+                    match chain.insert(header.clone()) {
+                        Ok(_) => {}
+                        Err(Error::UnknownValidators) => {
+                            request_validators(origin, &header);
+                            more = false;
+                            break;
                         }
-                        if err == Error::UnknownParent {
-                            let message = serialize(&MsgClass::SYNCREQ(blk.parent_hash), Infinite)
-                                .unwrap();
-                            ctx.send((origin, Operation::SINGLE, message)).unwrap();
+                        Err(err) => {
+                            warn!("insert header error {:?}", err);
+                            break;
                         }
                     }
                 }
+                if more {
+                    request_headers(origin);
+                } else {
+                    request_proofs(origin, &watch);
+                }
             }
-            MsgClass::SYNCREQ(hash) => {
-                info!("request block which hash is {:?}", hash);
-                match chain.get_block_by_hash(&hash) {
-                    Some(blk) => {
-                        let message = serialize(&MsgClass::BLOCK(blk), Infinite).unwrap();
-                        ctx.send((origin, Operation::SINGLE, message)).unwrap();
-                    }
-                    _ => {
-                        warn!("not found block by hash");
-                    }
+            MsgClass::VALIDATORS { block_hash, proof } => {
+                match chain.import_validators(&block_hash, &proof) {
+                    Ok(_) => request_headers(origin),
+                    Err(err) => warn!("invalid validators of {:?} from {}: {:?}", block_hash, origin, err),
                 }
-
             }
-            MsgClass::TX(stx) => {
-                let ret = chain.tx_basic_check(&stx);
-                if ret.is_ok() {
-                    let hash = stx.hash();             
-                    let ret = { tx_pool.write().enqueue(stx.clone(), hash) };
-                    if ret {
-                        let message = serialize(&MsgClass::TX(stx), Infinite).unwrap();
-                        ctx.send((origin, Operation::BROADCAST, message)).unwrap();
-                    }
+            MsgClass::STATUS(status) => {
+                if status.genesis_hash != genesis {
+                    ctx.send((origin, Operation::DISCONNECT, Vec::new())).unwrap();
+                } else if status.best_height > chain.get_status().0 {
+                    request_headers(origin);
                 } else {
-                    warn!("bad stx {:?}", ret);
+                    request_proofs(origin, &watch);
                 }
             }
-            MsgClass::MSG(m) => {
-                trace!("get msg {:?}", m);
+            MsgClass::TXPROOF { transaction_hash, block_hash, index, proof } => {
+                if chain.verify_transaction(&transaction_hash, &block_hash, &proof) {
+                    info!("transaction {:?} is in block {:?} at {}", transaction_hash, block_hash, index);
+                    watch.retain(|h| *h != transaction_hash);
+                } else {
+                    warn!("transaction {:?} not proven on our best chain by {}", transaction_hash, origin);
+                }
             }
+            _ => {}
+        }
+    }
+}
+
+/// Load the stored chain, exits if it can't be.
+fn init_chain(config: Arc<RwLock<SleepyConfig>>, db: Arc<Database>) -> Arc<Chain> {
+    match Chain::init(config, db) {
+        Ok(chain) => chain,
+        Err(e) => {
+            error!("load chain failed {:?}, restart with --repair", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn run_export(config: SleepyConfig, db: Database, args: &ArgMatches) {
+    let chain = init_chain(Arc::new(RwLock::new(config)), Arc::new(db));
+    let from = args.value_of("from").map_or(0, |v| v.parse().expect("invalid --from"));
+    let to = args.value_of("to").map_or(chain.current_height(), |v| v.parse().expect("invalid --to"));
+    let path = args.value_of("FILE").unwrap();
+    let mut file = BufWriter::new(File::create(path).expect("create export file failed"));
+    match export_blocks(&chain, from, to, &mut file) {
+        Ok(n) => info!("exported {} blocks to {}", n, path),
+        Err(e) => {
+            error!("export failed {:?}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn run_import(config: SleepyConfig, db: Database, args: &ArgMatches) {
+    let chain = init_chain(Arc::new(RwLock::new(config)), Arc::new(db));
+    let path = args.value_of("FILE").unwrap();
+    let mut file = BufReader::new(File::open(path).expect("open import file failed"));
+    match import_blocks(&chain, &mut file, !args.is_present("no-proof-check")) {
+        Ok(n) => info!("imported {} blocks from {}, best block {:?}", n, path, chain.get_status()),
+        Err(e) => {
+            error!("import failed {:?}", e);
+            process::exit(1);
         }
     }
-}
\ No newline at end of file
+}
+
+fn run_verify(config: SleepyConfig, db: Database) {
+    let config = Arc::new(RwLock::new(config));
+    let chain = init_chain(config.clone(), Arc::new(db));
+    let report = verify_chain(&chain, &config.read());
+    info!("checked {} blocks and {} transactions, {} stored unverified",
+          report.blocks, report.transactions, report.unverified);
+    if let Some((height, hash, e)) = report.invalid {
+        error!("invalid block {} {:?}: {:?}", height, hash, e);
+        process::exit(1);
+    }
+    info!("chain is valid up to block {}", chain.current_height());
+}
@@ -0,0 +1,139 @@
+//! Message handling of a full node, shared by the `sleepy` binary and the
+//! simulation.
+
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use bincode::{serialize, deserialize, Infinite};
+use parking_lot::{Mutex, RwLock};
+use chain::chain::Chain;
+use chain::error::Error;
+use network::connection::Operation;
+use network::msgclass::MsgClass;
+use network::sync::Synchronizer;
+use tx_pool::Pool;
+use strategy::Strategy;
+
+pub struct Handler {
+    chain: Arc<Chain>,
+    tx_pool: Arc<RwLock<Pool>>,
+    sync: Arc<Mutex<Synchronizer>>,
+    strategy: Arc<Mutex<Box<Strategy>>>,
+    sender: Sender<(u32, Operation, Vec<u8>)>,
+}
+
+impl Handler {
+    pub fn new(chain: Arc<Chain>,
+               tx_pool: Arc<RwLock<Pool>>,
+               sync: Arc<Mutex<Synchronizer>>,
+               strategy: Arc<Mutex<Box<Strategy>>>,
+               sender: Sender<(u32, Operation, Vec<u8>)>)
+               -> Self {
+        Handler {
+            chain: chain,
+            tx_pool: tx_pool,
+            sync: sync,
+            strategy: strategy,
+            sender: sender,
+        }
+    }
+
+    fn send(&self, origin: u32, op: Operation, msg: &MsgClass) {
+        let message = serialize(msg, Infinite).unwrap();
+        self.sender.send((origin, op, message)).unwrap();
+    }
+
+    /// Handle a message from `origin`, undecodable ones are dropped.
+    pub fn handle(&self, origin: u32, msg: &[u8]) {
+        trace!("get msg from {}", origin);
+        if self.sync.lock().is_banned(origin) {
+            return;
+        }
+        let decoded: MsgClass = match deserialize(msg) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("undecodable message from {}: {:?}", origin, e);
+                return;
+            }
+        };
+        for m in self.strategy.lock().received(&self.chain, origin, &decoded) {
+            self.send(origin, Operation::BROADCAST, &m);
+        }
+        match decoded {
+            MsgClass::BLOCK(blk) => {
+                trace!("get block {} from {}", blk.height, origin);
+                self.sync.lock().add_peer(origin);
+                match self.chain.insert_from(blk.clone(), Some(origin)) {
+                    Ok(_) => {}
+                    Err(err) => {
+                        if err != Error::DuplicateBlock {
+                            warn!("insert block error {:?}", err);
+                        }
+                        if err == Error::UnknownParent {
+                            // header sync only extends our best chain, walk
+                            // back parents as well in case the block is on a fork.
+                            if blk.height > self.chain.current_height() + 2 {
+                                self.sync.lock().start(origin, blk.height - 1);
+                            }
+                            self.send(origin, Operation::SINGLE, &MsgClass::SYNCREQ(blk.parent_hash));
+                        }
+                    }
+                }
+            }
+            MsgClass::SYNCREQ(hash) => {
+                info!("request block which hash is {:?}", hash);
+                match self.chain.get_block_by_hash(&hash) {
+                    Some(blk) => self.send(origin, Operation::SINGLE, &MsgClass::BLOCK(blk)),
+                    None => warn!("not found block by hash"),
+                }
+            }
+            MsgClass::TX(stx) => {
+                let ret = self.chain.tx_basic_check(&stx);
+                if ret.is_ok() {
+                    let hash = stx.hash();
+                    let queued = { self.tx_pool.write().enqueue(stx.clone(), hash) };
+                    if queued {
+                        self.send(origin, Operation::BROADCAST, &MsgClass::TX(stx));
+                    }
+                } else {
+                    warn!("bad stx {:?}", ret);
+                }
+            }
+            MsgClass::MSG(m) => {
+                trace!("get msg {:?}", m);
+            }
+            MsgClass::GETHEADERS { from_height, count } => {
+                self.sync.lock().on_get_headers(origin, from_height, count);
+            }
+            MsgClass::HEADERS(headers) => {
+                self.sync.lock().on_headers(origin, headers);
+            }
+            MsgClass::GETBODIES(hashes) => {
+                self.sync.lock().on_get_bodies(origin, hashes);
+            }
+            MsgClass::BODIES(bodies) => {
+                self.sync.lock().on_bodies(origin, bodies);
+            }
+            MsgClass::GETTXPROOF(hash) => {
+                if let Some((header, index, proof)) = self.chain.get_transaction_proof(&hash) {
+                    self.send(origin, Operation::SINGLE, &MsgClass::TXPROOF {
+                        transaction_hash: hash,
+                        block_hash: header.hash(),
+                        index: index as u64,
+                        proof: proof,
+                    });
+                }
+            }
+            MsgClass::GETVALIDATORS(hash) => {
+                if let Some(proof) = self.chain.get_validators_proof(&hash) {
+                    self.send(origin, Operation::SINGLE, &MsgClass::VALIDATORS { block_hash: hash, proof: proof });
+                }
+            }
+            MsgClass::TXPROOF { .. } | MsgClass::VALIDATORS { .. } => {}
+            MsgClass::STATUS(status) => {
+                if !self.sync.lock().on_status(origin, status) {
+                    self.sender.send((origin, Operation::DISCONNECT, Vec::new())).unwrap();
+                }
+            }
+        }
+    }
+}
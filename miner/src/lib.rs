@@ -8,64 +8,117 @@ extern crate bincode;
 extern crate parking_lot;
 extern crate tx_pool;
 
-use std::sync::mpsc::Sender;
+pub mod strategy;
+pub mod handler;
+
+use std::collections::HashSet;
+use std::sync::mpsc::{channel, Receiver, Sender};
 use chain::chain::Chain;
 use chain::block::Block;
 use std::thread;
 use std::time::Duration;
-use util::hash::H256;
-use util::Hashable;
 use std::sync::Arc;
 use network::connection::Operation;
 use util::config::SleepyConfig;
+use util::clock::Clock;
+use util::H256;
 use bincode::{serialize, Infinite};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use network::msgclass::MsgClass;
 use tx_pool::Pool;
+use strategy::Strategy;
 
+/// Start mining, the returned receiver gets the blocks we generate.
+///
+/// `strategy` picks the parent and timestamp of our blocks, and what we
+/// send once one is mined.
 pub fn start_miner(tx: Sender<(u32, Operation, Vec<u8>)>,
                    chain: Arc<Chain>,
                    config: Arc<RwLock<SleepyConfig>>,
-                   tx_pool: Arc<RwLock<Pool>>) {
+                   tx_pool: Arc<RwLock<Pool>>,
+                   clock: Arc<Clock>,
+                   strategy: Arc<Mutex<Box<Strategy>>>) -> Receiver<Block> {
     
+    let (mined_tx, mined_rx) = channel();
     let tx = tx.clone();
     let chain = chain.clone();
     let config = config.clone();
     let tx_pool = tx_pool.clone();
     thread::spawn(move || {
         info!("start mining!");
-        let mut time = match {config.read().ntp_now()} {
-            Some(t) => t,
-            _ => panic!("NTP Error"),
+        // the clock may not be synced yet.
+        let mut time = loop {
+            match clock.now() {
+                Some(t) => break t,
+                None => thread::sleep(Duration::from_millis(100)),
+            }
         };
+        // equivocations reported since the start, by header hashes.
+        let mut reported = HashSet::new();
         loop {
-            if let Some(new_time) = {config.read().ntp_now()} {
+            if let Some(new_time) = clock.now() {
                 if time < new_time {
                     time = new_time;
-                    let (height, hash) = chain.get_status();
-                    let miner_privkey = {config.read().get_miner_private_key()};
-                    let anc_hash = chain.anc_hash(height, hash).unwrap();
-                    
-                    let sig = Block::gen_proof(miner_privkey, time, height + 1, anc_hash);
-                    let proof = sig.sha3();
-                    let difficulty: H256 = {config.read().get_difficulty().into()};
-
-                    if proof < difficulty {               
-                        let id = {config.read().get_id()};
-                        let (tx_list, hash_list) = { tx_pool.write().package() };
-                        let signed_blk = chain.gen_block(height, hash, time, sig, tx_list);
-                        { tx_pool.write().update(&hash_list) };
-                        info!("generate block at timestamp {}", time);
-                        let msg = MsgClass::BLOCK(signed_blk);
-                        let message = serialize(&msg, Infinite).unwrap();
-                        tx.send((id, Operation::BROADCAST, message)).unwrap();             
+                    if let Some(block) = mine(time, &chain, &config, &tx_pool, &strategy, &mut reported, &tx) {
+                        // nobody may be listening.
+                        let _ = mined_tx.send(block);
                     }
                 }
             } else {
-                info!("ntp error!!");
+                info!("clock error!!");
             }
             
             thread::sleep(Duration::from_millis(100 / {config.read().nps}));
         }
     });
+    mined_rx
+}
+
+/// One mining step at `time`: report the equivocations not in `reported`,
+/// then try the lottery for the slot and broadcast what we mine.
+pub fn mine(time: u64,
+            chain: &Chain,
+            config: &RwLock<SleepyConfig>,
+            tx_pool: &RwLock<Pool>,
+            strategy: &Mutex<Box<Strategy>>,
+            reported: &mut HashSet<(H256, H256)>,
+            tx: &Sender<(u32, Operation, Vec<u8>)>) -> Option<Block> {
+    let id = {config.read().get_id()};
+    for evidence in chain.equivocations() {
+        // the offender may be removed or queued for removal
+        // already, e.g. reported before a restart.
+        if reported.contains(&evidence.hashes()) || chain.check_evidence(&evidence).is_err() {
+            continue;
+        }
+        reported.insert(evidence.hashes());
+        let key = {config.read().get_signer_private_key()};
+        let stx = evidence.to_transaction(time, &key);
+        info!("report equivocation by {:?} at {}", evidence.signer, evidence.timestamp);
+        let queued = { tx_pool.write().enqueue(stx.clone(), stx.hash()) };
+        if queued {
+            let message = serialize(&MsgClass::TX(stx), Infinite).unwrap();
+            tx.send((id, Operation::BROADCAST, message)).unwrap();
+        }
+    }
+
+    // the handler takes the strategy per message, don't hold it while generating.
+    let ((height, hash), stamp) = {
+        let mut strategy = strategy.lock();
+        (strategy.parent(chain, chain.get_status()), strategy.timestamp(time))
+    };
+    let anc_hash = chain.anc_hash(height, hash)?;
+    let sig = chain.consensus().gen_proof(stamp, height + 1, anc_hash);
+    if !chain.consensus().is_eligible(&sig) {
+        return None;
+    }
+    let (tx_list, hash_list) = { tx_pool.write().package() };
+    let block = chain.gen_block(height, hash, stamp, sig, tx_list);
+    { tx_pool.write().update(&hash_list) };
+    info!("generate block at timestamp {}", stamp);
+    let msgs = { strategy.lock().mined(chain, block.clone()) };
+    for msg in msgs {
+        let message = serialize(&msg, Infinite).unwrap();
+        tx.send((id, Operation::BROADCAST, message)).unwrap();
+    }
+    Some(block)
 }
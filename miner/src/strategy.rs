@@ -0,0 +1,199 @@
+//! Mining and relaying strategies.
+//!
+//! Honest nodes use `Honest`, the others misbehave on purpose so Byzantine
+//! tests run against real attacks. The strategy is asked by the miner which
+//! parent and timestamp to use and what to send once a block is mined, and
+//! by the message handler what to send when a message arrives.
+
+use util::{H256, Hashable};
+use util::config::{Behaviour, ByzantineConfig};
+use crypto::sign;
+use chain::chain::Chain;
+use chain::block::Block;
+use chain::transaction::{SignedTransaction, Transaction};
+use network::msgclass::MsgClass;
+
+pub trait Strategy: Send {
+    /// Block to mine on, given the best one.
+    fn parent(&mut self, _chain: &Chain, best: (u64, H256)) -> (u64, H256) {
+        best
+    }
+
+    /// Timestamp of the block mined in the slot `now`.
+    fn timestamp(&mut self, now: u64) -> u64 {
+        now
+    }
+
+    /// Messages to broadcast once `block` is mined.
+    fn mined(&mut self, _chain: &Chain, block: Block) -> Vec<MsgClass> {
+        vec![MsgClass::BLOCK(block)]
+    }
+
+    /// Extra messages to broadcast when `msg` arrives from `origin`.
+    fn received(&mut self, _chain: &Chain, _origin: u32, _msg: &MsgClass) -> Vec<MsgClass> {
+        Vec::new()
+    }
+}
+
+pub struct Honest;
+
+impl Strategy for Honest {}
+
+/// Selfish mining: keep mined blocks private until `count` of them are
+/// withheld or the public chain reaches the first of them.
+pub struct Withhold {
+    count: usize,
+    withheld: Vec<Block>,
+}
+
+impl Withhold {
+    pub fn new(count: u64) -> Self {
+        Withhold {
+            count: count as usize,
+            withheld: Vec::new(),
+        }
+    }
+
+    fn release(&mut self) -> Vec<MsgClass> {
+        info!("release {} withheld blocks", self.withheld.len());
+        self.withheld.drain(..).map(MsgClass::BLOCK).collect()
+    }
+}
+
+impl Strategy for Withhold {
+    fn mined(&mut self, _chain: &Chain, block: Block) -> Vec<MsgClass> {
+        self.withheld.push(block);
+        if self.withheld.len() >= self.count {
+            return self.release();
+        }
+        Vec::new()
+    }
+
+    fn received(&mut self, _chain: &Chain, _origin: u32, msg: &MsgClass) -> Vec<MsgClass> {
+        match (msg, self.withheld.first().map(|b| b.height)) {
+            (&MsgClass::BLOCK(ref blk), Some(first)) if blk.height >= first => self.release(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Mine on the ancestor `depth` blocks below the best one.
+pub struct StaleParent {
+    depth: u64,
+}
+
+impl StaleParent {
+    pub fn new(depth: u64) -> Self {
+        StaleParent { depth: depth }
+    }
+}
+
+impl Strategy for StaleParent {
+    fn parent(&mut self, chain: &Chain, best: (u64, H256)) -> (u64, H256) {
+        let (height, hash) = best;
+        let stale = height.saturating_sub(self.depth);
+        match chain.block_hash_by_number_fork(stale, height, hash) {
+            Some(h) => (stale, h),
+            None => best,
+        }
+    }
+}
+
+/// Sign a second block with a marker transaction for every slot won.
+pub struct Equivocate {
+    key: H256,
+}
+
+impl Equivocate {
+    pub fn new(key: H256) -> Self {
+        Equivocate { key: key }
+    }
+}
+
+impl Strategy for Equivocate {
+    fn mined(&mut self, chain: &Chain, block: Block) -> Vec<MsgClass> {
+        let tx = Transaction {
+            data: b"sleepy/byzantine".to_vec(),
+            timestamp: block.timestamp,
+        };
+        let hash = tx.cal_hash();
+        let stx = SignedTransaction {
+            transaction: tx,
+            hash: hash,
+            signature: sign(&self.key, &hash).expect("failed to sign marker transaction").into(),
+        };
+        let twin = chain.gen_block(block.height - 1,
+                                   block.parent_hash,
+                                   block.timestamp,
+                                   block.proof.time_signature.clone(),
+                                   vec![stx]);
+        info!("equivocate at {}", block.timestamp);
+        vec![MsgClass::BLOCK(block), MsgClass::BLOCK(twin)]
+    }
+}
+
+/// Stamp blocks `ticks` ahead of the clock.
+pub struct FutureTimestamp {
+    ticks: u64,
+}
+
+impl FutureTimestamp {
+    pub fn new(ticks: u64) -> Self {
+        FutureTimestamp { ticks: ticks }
+    }
+}
+
+impl Strategy for FutureTimestamp {
+    fn timestamp(&mut self, now: u64) -> u64 {
+        now + self.ticks
+    }
+}
+
+/// Send `count` blocks with made up parents along with every mined block.
+///
+/// They carry the winning proof so they pass the eligibility check and end
+/// up in the orphan buffers of the receivers.
+pub struct OrphanFlood {
+    count: u64,
+    key: H256,
+}
+
+impl OrphanFlood {
+    pub fn new(count: u64, key: H256) -> Self {
+        OrphanFlood {
+            count: count,
+            key: key,
+        }
+    }
+}
+
+impl Strategy for OrphanFlood {
+    fn mined(&mut self, _chain: &Chain, block: Block) -> Vec<MsgClass> {
+        let mut msgs = Vec::new();
+        for i in 0..self.count {
+            let mut seed = block.hash().to_vec();
+            seed.extend_from_slice(&H256::from(i).to_vec());
+            let mut orphan = Block::init(block.height + 1 + i,
+                                         block.timestamp,
+                                         seed.sha3(),
+                                         Vec::new(),
+                                         block.proof.time_signature.clone());
+            orphan.sign(&self.key);
+            msgs.push(MsgClass::BLOCK(orphan));
+        }
+        msgs.push(MsgClass::BLOCK(block));
+        msgs
+    }
+}
+
+/// Build the strategy selected in the config.
+pub fn from_config(config: &ByzantineConfig, signer_private_key: H256) -> Box<Strategy> {
+    match config.behaviour {
+        Behaviour::Honest => Box::new(Honest),
+        Behaviour::Withhold => Box::new(Withhold::new(config.withhold_blocks)),
+        Behaviour::StaleParent => Box::new(StaleParent::new(config.stale_depth)),
+        Behaviour::Equivocate => Box::new(Equivocate::new(signer_private_key)),
+        Behaviour::FutureTimestamp => Box::new(FutureTimestamp::new(config.future_ticks)),
+        Behaviour::OrphanFlood => Box::new(OrphanFlood::new(config.orphans, signer_private_key)),
+    }
+}
@@ -8,7 +8,8 @@ use std::sync::Arc;
 use std::io::prelude::*;
 use std::net::TcpStream;
 use util::config;
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, Sender};
+use std::collections::HashSet;
 
 const TIMEOUT: u64 = 15;
 
@@ -19,6 +20,8 @@ pub enum Operation {
     BROADCAST = 0,
     SINGLE = 1,
     SUBTRACT = 2,
+    /// Drop the connection to the peer and stop reconnecting.
+    DISCONNECT = 3,
 }
 
 impl ::std::marker::Copy for Operation {}
@@ -26,6 +29,7 @@ impl ::std::marker::Copy for Operation {}
 pub struct Connection {
     pub id_card: u32,
     pub peers_pair: PeerPairs,
+    pub banned: Arc<RwLock<HashSet<u32>>>,
 }
 
 impl Connection {
@@ -42,19 +46,25 @@ impl Connection {
         Connection {
             id_card,
             peers_pair,
+            banned: Arc::new(RwLock::new(HashSet::new())),
         }
     }
 }
 
-pub fn do_connect(con: &Connection) {
-    for &(_, addr, ref stream) in &con.peers_pair {
+/// Keep connected to the peers, `on_connect` gets the id of each new connection.
+pub fn do_connect(con: &Connection, on_connect: Sender<u32>) {
+    for &(id_card, addr, ref stream) in &con.peers_pair {
         let stream_lock = stream.clone();
+        let banned = con.banned.clone();
+        let on_connect = on_connect.clone();
         thread::spawn(move || loop {
-                          {
+                          if !banned.read().contains(&id_card) {
                               let stream_opt = &mut *stream_lock.as_ref().write();
+                              let mut connected = false;
                               if stream_opt.is_none() {
                                   trace!("connet {:?}", addr);
                                   let stream = TcpStream::connect(addr).ok();
+                                  connected = stream.is_some();
                                   *stream_opt = stream;
                               }
 
@@ -72,6 +82,8 @@ pub fn do_connect(con: &Connection) {
 
                               if need_reconnect {
                                   *stream_opt = None;
+                              } else if connected {
+                                  let _ = on_connect.send(id_card);
                               }
                           }
 
@@ -82,6 +94,17 @@ pub fn do_connect(con: &Connection) {
     }
 }
 
+/// Drop the connection to the peer, it won't be connected again.
+pub fn disconnect(con: &Connection, peer: u32) {
+    con.banned.write().insert(peer);
+    for &(id_card, _, ref stream) in &con.peers_pair {
+        if id_card == peer {
+            *stream.write() = None;
+        }
+    }
+    info!("disconnect from {}", peer);
+}
+
 pub fn broadcast(con: &Connection, msg: Vec<u8>, origin: u32, operate: Operation) {
     let request_id = 0xDEADBEEF00000000 + msg.len() + 4;
     let mut encoded_request_id = [0; 8];
@@ -118,14 +141,18 @@ pub fn is_send(id_card: u32, origin: u32, operate: Operation) -> bool {
     (operate == Operation::SUBTRACT && origin != id_card)
 }
 
-pub fn start_client(config: &config::SleepyConfig, rx: Receiver<(u32, Operation, Vec<u8>)>) {
+pub fn start_client(config: &config::SleepyConfig, rx: Receiver<(u32, Operation, Vec<u8>)>, on_connect: Sender<u32>) {
     let con = Connection::new(config);
-    do_connect(&con);
+    do_connect(&con, on_connect);
     thread::spawn(move || {
                       info!("start client!");
                       loop {
                           let (origin, op, msg) = rx.recv().unwrap();
-                          broadcast(&con, msg, origin, op);
+                          if op == Operation::DISCONNECT {
+                              disconnect(&con, origin);
+                          } else {
+                              broadcast(&con, msg, origin, op);
+                          }
                       }
                   });
 }
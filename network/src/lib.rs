@@ -12,6 +12,11 @@ extern crate parking_lot;
 extern crate util;
 extern crate serde;
 extern crate chain;
+extern crate bincode;
+#[cfg(test)]
+extern crate kvdb;
+#[cfg(test)]
+extern crate crypto;
 #[macro_use]
 extern crate serde_derive;
 
@@ -19,4 +24,5 @@ pub mod server;
 pub mod connection;
 pub mod protocol;
 pub mod msgclass;
-pub mod msghandle;
\ No newline at end of file
+pub mod msghandle;
+pub mod sync;
\ No newline at end of file
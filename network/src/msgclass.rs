@@ -1,11 +1,27 @@
-use chain::block::Block;
+use chain::block::{Block, Body, Header};
 use chain::transaction::SignedTransaction;
 use util::hash::H256;
+use util::ProofNode;
+use sync::Status;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum MsgClass {
     BLOCK(Block),
     SYNCREQ(H256),
     TX(SignedTransaction),
     MSG(Vec<u8>),
+    /// Ask for `count` canonical headers starting at `from_height`.
+    GETHEADERS { from_height: u64, count: u64 },
+    HEADERS(Vec<Header>),
+    /// Ask for the bodies of blocks with given hashes.
+    GETBODIES(Vec<H256>),
+    BODIES(Vec<(H256, Body)>),
+    STATUS(Status),
+    /// Ask for the inclusion proof of a transaction.
+    GETTXPROOF(H256),
+    TXPROOF { transaction_hash: H256, block_hash: H256, index: u64, proof: Vec<ProofNode> },
+    /// Ask for the validator set in the state of given block.
+    GETVALIDATORS(H256),
+    /// State trie nodes proving the validator set of `block_hash`.
+    VALIDATORS { block_hash: H256, proof: Vec<Vec<u8>> },
 }
\ No newline at end of file
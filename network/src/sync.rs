@@ -0,0 +1,606 @@
+//! Header-first batch sync.
+//!
+//! Headers are downloaded in batches from one peer and their proofs are
+//! checked before any body is requested. Bodies are then requested in
+//! chunks spread over all known peers, and blocks are imported in height
+//! order as their bodies arrive.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use bincode::{serialize, Infinite};
+use chain::chain::Chain;
+use chain::block::{Block, Body, Header};
+use chain::error::Error;
+use util::hash::H256;
+use connection::Operation;
+use msgclass::MsgClass;
+
+/// Version of the sync messages, peers with another version are dropped.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Most headers sent in one `HEADERS` message.
+pub const MAX_HEADERS: u64 = 256;
+/// Most bodies requested in one `GETBODIES` message.
+pub const MAX_BODIES: usize = 64;
+/// Most downloaded headers waiting for their bodies.
+const MAX_PENDING_HEADERS: usize = 2048;
+/// Seconds after which a request is sent again to another peer.
+const REQUEST_TIMEOUT: u64 = 10;
+
+/// Summary of a node's chain, sent on connect and periodically.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub protocol_version: u32,
+    pub genesis_hash: H256,
+    pub best_height: u64,
+    pub best_hash: H256,
+}
+
+pub struct Synchronizer {
+    chain: Arc<Chain>,
+    sender: Sender<(u32, Operation, Vec<u8>)>,
+    peers: Vec<u32>,
+    /// Peers on another chain or protocol version.
+    banned: HashSet<u32>,
+    next_peer: usize,
+    /// Height we try to reach.
+    target: u64,
+    /// Verified headers waiting for their bodies.
+    headers: BTreeMap<u64, Header>,
+    /// Height to download from when the peer's branch forks below our best block.
+    fork_from: Option<u64>,
+    bodies: HashMap<H256, Body>,
+    header_request: Option<(u32, Instant)>,
+    body_requests: HashMap<H256, (u32, Instant)>,
+}
+
+impl Synchronizer {
+    pub fn new(chain: Arc<Chain>, sender: Sender<(u32, Operation, Vec<u8>)>) -> Self {
+        Synchronizer {
+            chain: chain,
+            sender: sender,
+            peers: Vec::new(),
+            banned: HashSet::new(),
+            next_peer: 0,
+            target: 0,
+            headers: BTreeMap::new(),
+            fork_from: None,
+            bodies: HashMap::new(),
+            header_request: None,
+            body_requests: HashMap::new(),
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: u32) {
+        if !self.banned.contains(&peer) && !self.peers.contains(&peer) {
+            self.peers.push(peer);
+        }
+    }
+
+    pub fn remove_peer(&mut self, peer: u32) {
+        self.peers.retain(|p| *p != peer);
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_banned(&self, peer: u32) -> bool {
+        self.banned.contains(&peer)
+    }
+
+    /// Our status.
+    pub fn status(&self) -> Status {
+        let (best_height, best_hash) = self.chain.get_status();
+        Status {
+            protocol_version: PROTOCOL_VERSION,
+            genesis_hash: self.chain.genesis_hash(),
+            best_height: best_height,
+            best_hash: best_hash,
+        }
+    }
+
+    pub fn send_status(&self, peer: u32) {
+        self.send(peer, &MsgClass::STATUS(self.status()));
+    }
+
+    /// Handle the status of a peer, start syncing if it is ahead.
+    ///
+    /// Returns false if the peer is on another chain and must be disconnected.
+    pub fn on_status(&mut self, peer: u32, status: Status) -> bool {
+        if status.protocol_version != PROTOCOL_VERSION || status.genesis_hash != self.chain.genesis_hash() {
+            warn!("peer {} has genesis {:?} and version {}", peer, status.genesis_hash, status.protocol_version);
+            self.remove_peer(peer);
+            self.banned.insert(peer);
+            return false;
+        }
+        self.add_peer(peer);
+        if self.chain.get_block_header_by_hash(&status.best_hash).is_none() {
+            self.start(peer, status.best_height);
+        }
+        true
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        self.target > self.chain.current_height()
+    }
+
+    /// Start syncing up to `target`, which `peer` is known to have.
+    pub fn start(&mut self, peer: u32, target: u64) {
+        self.add_peer(peer);
+        if target <= self.target || target <= self.chain.current_height() {
+            return;
+        }
+        info!("sync to {} from {}", target, peer);
+        self.target = target;
+        if self.header_request.is_none() {
+            self.request_headers(peer);
+        }
+    }
+
+    fn send(&self, peer: u32, msg: &MsgClass) {
+        let message = serialize(msg, Infinite).unwrap();
+        self.sender.send((peer, Operation::SINGLE, message)).unwrap();
+    }
+
+    fn pick_peer(&mut self) -> Option<u32> {
+        if self.peers.is_empty() {
+            return None;
+        }
+        self.next_peer = (self.next_peer + 1) % self.peers.len();
+        Some(self.peers[self.next_peer])
+    }
+
+    /// Height of the next header to download.
+    fn next_height(&self) -> u64 {
+        match self.headers.keys().next_back() {
+            Some(h) => h + 1,
+            None => self.fork_from.unwrap_or(self.chain.current_height() + 1),
+        }
+    }
+
+    fn request_headers(&mut self, peer: u32) {
+        let from = self.next_height();
+        // the validators of the next epoch are known once its first parent is imported.
+        let waiting = self.headers.contains_key(&self.chain.validators_height(from));
+        if from > self.target || self.headers.len() >= MAX_PENDING_HEADERS || waiting {
+            self.header_request = None;
+            return;
+        }
+        let count = ::std::cmp::min(MAX_HEADERS, self.target - from + 1);
+        self.send(peer, &MsgClass::GETHEADERS { from_height: from, count: count });
+        self.header_request = Some((peer, Instant::now()));
+    }
+
+    fn request_bodies(&mut self) {
+        let missing: Vec<H256> = self.headers.values()
+            .map(|h| h.hash())
+            .filter(|h| !self.bodies.contains_key(h) && !self.body_requests.contains_key(h))
+            .collect();
+        for chunk in missing.chunks(MAX_BODIES) {
+            let peer = match self.pick_peer() {
+                Some(p) => p,
+                None => return,
+            };
+            let now = Instant::now();
+            for h in chunk {
+                self.body_requests.insert(*h, (peer, now));
+            }
+            self.send(peer, &MsgClass::GETBODIES(chunk.to_vec()));
+        }
+    }
+
+    pub fn on_get_headers(&self, peer: u32, from_height: u64, count: u64) {
+        let headers = self.chain.get_headers(from_height, ::std::cmp::min(count, MAX_HEADERS));
+        self.send(peer, &MsgClass::HEADERS(headers));
+    }
+
+    pub fn on_get_bodies(&self, peer: u32, hashes: Vec<H256>) {
+        let hashes: Vec<H256> = hashes.into_iter().take(MAX_BODIES).collect();
+        self.send(peer, &MsgClass::BODIES(self.chain.get_bodies(&hashes)));
+    }
+
+    /// Lottery seed of the header at `height`, from downloaded or imported headers.
+    fn seed(&self, height: u64) -> Option<H256> {
+        let anc_height = self.chain.anc_height(height);
+        let header = match self.headers.get(&anc_height) {
+            Some(h) => h.clone(),
+            None => self.chain.block_hash_by_number(anc_height)
+                .and_then(|h| self.chain.get_block_header_by_hash(&h))?
+                .header,
+        };
+        Some(self.chain.consensus().seed(&header))
+    }
+
+    fn parent_known(&self, header: &Header) -> bool {
+        match self.headers.get(&(header.height - 1)) {
+            Some(parent) => parent.hash() == header.parent_hash,
+            None => self.chain.get_block_header_by_hash(&header.parent_hash).is_some(),
+        }
+    }
+
+    /// Hash of the block holding the validator set of `header`, `None` while
+    /// that block is only downloaded.
+    fn epoch_hash(&self, header: &Header) -> Option<H256> {
+        let height = self.chain.validators_height(header.height);
+        if self.headers.contains_key(&height) {
+            return None;
+        }
+        // go down the downloaded headers to the imported part of the branch.
+        let (from, parent) = match self.headers.values().next() {
+            Some(first) if first.height < header.height => (first.height - 1, first.parent_hash),
+            _ => (header.height - 1, header.parent_hash),
+        };
+        self.chain.block_hash_by_number_fork(height, from, parent)
+    }
+
+    fn check_header(&self, header: &Header) -> Result<(), Error> {
+        header.check_hash()?;
+        if header.height == 0 || header.height != self.next_height() {
+            return Err(Error::InvalidFormat);
+        }
+        if !self.parent_known(header) {
+            return Err(Error::UnknownParent);
+        }
+        let seed = self.seed(header.height).ok_or(Error::UnknownAncestor)?;
+        let epoch = self.epoch_hash(header).ok_or(Error::UnknownAncestor)?;
+        self.chain.verify_header(header, seed, &epoch)
+    }
+
+    pub fn on_headers(&mut self, peer: u32, headers: Vec<Header>) {
+        match self.header_request {
+            Some((p, _)) if p == peer => self.header_request = None,
+            _ => return,
+        }
+
+        if headers.is_empty() {
+            // the peer has nothing more, stop at what we have.
+            self.target = self.next_height() - 1;
+            return;
+        }
+
+        let full = headers.len() as u64 == MAX_HEADERS;
+        for (i, header) in headers.into_iter().enumerate() {
+            match self.check_header(&header) {
+                Ok(_) => {}
+                // the next epoch, checked once this one is imported.
+                Err(Error::UnknownAncestor) => break,
+                // the peer is on a branch we don't have.
+                Err(Error::UnknownParent) if i == 0 => {
+                    // the downloaded headers are on another branch, ask again once imported.
+                    if !self.headers.is_empty() {
+                        return;
+                    }
+                    if self.back_off(header.height) {
+                        self.request_headers(peer);
+                    }
+                    return;
+                }
+                Err(e) => {
+                    warn!("bad header {} from {}: {:?}", header.height, peer, e);
+                    if is_bad_data(&e) {
+                        self.remove_peer(peer);
+                    }
+                    break;
+                }
+            }
+            self.fork_from = None;
+            self.headers.insert(header.height, header);
+        }
+
+        self.request_bodies();
+        if full || self.next_height() <= self.target {
+            match self.pick_peer() {
+                Some(p) => self.request_headers(p),
+                None => self.target = 0,
+            }
+        }
+    }
+
+    /// Download again from below `height`, down to the block after the final
+    /// one. Returns false, and stops syncing, if there is nothing lower to ask.
+    fn back_off(&mut self, height: u64) -> bool {
+        let (finalized, _) = self.chain.finalized();
+        let from = ::std::cmp::max(height.saturating_sub(MAX_HEADERS), finalized + 1);
+        if from >= height {
+            self.target = self.chain.current_height();
+            return false;
+        }
+        self.fork_from = Some(from);
+        true
+    }
+
+    pub fn on_bodies(&mut self, peer: u32, bodies: Vec<(H256, Body)>) {
+        for (hash, body) in bodies {
+            match self.body_requests.get(&hash) {
+                Some(&(p, _)) if p == peer => {}
+                _ => continue,
+            }
+            self.body_requests.remove(&hash);
+            let valid = self.headers.values().any(|h| h.hash() == hash && h.transactions_root == body.transactions_root());
+            if valid {
+                self.bodies.insert(hash, body);
+            }
+        }
+        self.import_ready();
+        self.request_bodies();
+    }
+
+    /// Import the blocks whose body arrived, lowest first.
+    fn import_ready(&mut self) {
+        loop {
+            let height = match self.headers.keys().next() {
+                Some(h) => *h,
+                None => break,
+            };
+            let hash = self.headers[&height].hash();
+            let body = match self.bodies.remove(&hash) {
+                Some(b) => b,
+                None => break,
+            };
+            let header = self.headers.remove(&height).expect("height taken from the map");
+            match self.chain.insert(Block { header: header, body: body }) {
+                Ok(_) | Err(Error::DuplicateBlock) => {}
+                Err(e) => {
+                    warn!("sync import {} failed: {:?}", height, e);
+                    self.reset();
+                    return;
+                }
+            }
+        }
+
+        if self.header_request.is_none() && self.is_syncing() {
+            if let Some(p) = self.pick_peer() {
+                self.request_headers(p);
+            }
+        }
+    }
+
+    /// Drop all downloaded data.
+    fn reset(&mut self) {
+        self.target = 0;
+        self.headers.clear();
+        self.fork_from = None;
+        self.bodies.clear();
+        self.header_request = None;
+        self.body_requests.clear();
+    }
+
+    /// Send again the requests that timed out, to other peers.
+    pub fn tick(&mut self) {
+        let timeout = Duration::from_secs(REQUEST_TIMEOUT);
+        if let Some((peer, t)) = self.header_request {
+            if t.elapsed() > timeout {
+                self.header_request = None;
+                let mut next = self.pick_peer();
+                if next == Some(peer) {
+                    next = self.pick_peer();
+                }
+                if let Some(p) = next {
+                    self.request_headers(p);
+                }
+            }
+        }
+
+        let before = self.body_requests.len();
+        self.body_requests.retain(|_, &mut (_, t)| t.elapsed() <= timeout);
+        if self.body_requests.len() != before {
+            self.request_bodies();
+        }
+    }
+}
+
+/// Whether a failed header check proves the peer sent bad data.
+fn is_bad_data(e: &Error) -> bool {
+    match *e {
+        Error::InvalidFormat | Error::UnknownParent | Error::InvalidProof | Error::InvalidSignature |
+        Error::InvalidPublicKey | Error::InvalidProofKey => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver};
+    use bincode::deserialize;
+    use parking_lot::RwLock;
+    use kvdb::in_memory;
+    use crypto::{sign, KeyPair};
+    use util::config::{Config, SleepyConfig, KeyGroup, ForkChoiceRule, BlockBufferConfig, RpcConfig, ClockSource,
+                       ByzantineConfig};
+    use chain::consensus::Consensus;
+    use chain::db;
+    use chain::transaction::SignedTransaction;
+    use chain::validator::{Validator, ValidatorChange};
+
+    /// Every proof wins, and it is valid when it is the signer's proof key.
+    struct KeyLottery;
+
+    impl Consensus for KeyLottery {
+        fn anc_height(&self, _height: u64) -> u64 {
+            0
+        }
+
+        fn seed(&self, _ancestor: &Header) -> H256 {
+            H256::default()
+        }
+
+        fn gen_proof(&self, _time: u64, _height: u64, _seed: H256) -> Vec<u8> {
+            Vec::new()
+        }
+
+        fn is_eligible(&self, _proof: &[u8]) -> bool {
+            true
+        }
+
+        fn verify_proof(&self, header: &Header, _seed: H256, public_key: Vec<u8>, _g: Vec<u8>) -> bool {
+            header.proof.time_signature == public_key
+        }
+    }
+
+    fn keygroup(key: &H256) -> KeyGroup {
+        KeyGroup {
+            proof_public_key: key.to_vec(),
+            proof_public_g: Vec::new(),
+            signer_public_key: *KeyPair::from_privkey(*key).unwrap().pubkey(),
+        }
+    }
+
+    fn test_config(validators: &[H256]) -> Arc<RwLock<SleepyConfig>> {
+        let config = Config {
+            id_card: 0,
+            port: 40000,
+            max_peer: 2,
+            steps: 6,
+            nps: 10,
+            miner_private_key: Vec::new(),
+            signer_private_key: validators[0],
+            peers: Vec::new(),
+            keygroups: validators.iter().map(keygroup).collect(),
+            epoch_len: 5,
+            start_time: 0,
+            ntp_servers: Vec::new(),
+            buffer_size: 5,
+            fork_choice: ForkChoiceRule::default(),
+            finality_depth: 20,
+            block_buffer: BlockBufferConfig::default(),
+            rpc: RpcConfig::default(),
+            clock: ClockSource::System,
+            byzantine: ByzantineConfig::default(),
+        };
+        Arc::new(RwLock::new(SleepyConfig { config: config, public_keys: HashMap::new() }))
+    }
+
+    fn test_chain(config: Arc<RwLock<SleepyConfig>>) -> Arc<Chain> {
+        let db = in_memory(db::NUM_COLUMNS.unwrap());
+        Chain::init_with_consensus(config, Arc::new(db), Box::new(KeyLottery)).unwrap()
+    }
+
+    /// Mine `count` blocks on the best one, signed by `signer`.
+    fn mine(chain: &Chain, config: &RwLock<SleepyConfig>, signer: H256, count: u64, txs: Vec<SignedTransaction>) {
+        config.write().signer_private_key = signer;
+        for i in 0..count {
+            let (height, hash) = chain.get_status();
+            let txs = if i == 0 { txs.clone() } else { Vec::new() };
+            chain.gen_block(height, hash, height + 1, signer.to_vec(), txs);
+        }
+    }
+
+    fn sent(rx: &Receiver<(u32, Operation, Vec<u8>)>) -> Vec<(u32, MsgClass)> {
+        rx.try_iter().map(|(peer, op, msg)| {
+            assert_eq!(op, Operation::SINGLE);
+            (peer, deserialize(&msg).unwrap())
+        }).collect()
+    }
+
+    fn hashes(chain: &Chain, from: u64, count: u64) -> Vec<H256> {
+        chain.get_headers(from, count).iter().map(|h| h.hash()).collect()
+    }
+
+    #[test]
+    fn headers_then_bodies() {
+        let a = H256::from(1);
+        let config = test_config(&[a]);
+        let source = test_chain(config.clone());
+        mine(&source, &config, a, 3, Vec::new());
+
+        let (tx, rx) = channel();
+        let node = test_chain(test_config(&[a]));
+        let mut sync = Synchronizer::new(node.clone(), tx);
+        sync.start(1, 3);
+        assert_eq!(sent(&rx), vec![(1, MsgClass::GETHEADERS { from_height: 1, count: 3 })]);
+
+        sync.on_headers(1, source.get_headers(1, 3));
+        assert_eq!(sent(&rx), vec![(1, MsgClass::GETBODIES(hashes(&source, 1, 3)))]);
+        assert_eq!(node.current_height(), 0);
+
+        sync.on_bodies(1, source.get_bodies(&hashes(&source, 1, 3)));
+        assert_eq!(node.get_status(), source.get_status());
+        assert!(!sync.is_syncing());
+        assert!(sent(&rx).is_empty());
+    }
+
+    #[test]
+    fn bad_headers_fall_back_to_another_peer() {
+        let (a, outsider) = (H256::from(1), H256::from(2));
+        let config = test_config(&[a]);
+        let source = test_chain(config.clone());
+        mine(&source, &config, outsider, 2, Vec::new());
+
+        let (tx, rx) = channel();
+        let mut sync = Synchronizer::new(test_chain(test_config(&[a])), tx);
+        sync.add_peer(2);
+        sync.start(1, 2);
+        // not asked.
+        sync.on_headers(2, source.get_headers(1, 2));
+        sync.on_headers(1, source.get_headers(1, 2));
+
+        let request = MsgClass::GETHEADERS { from_height: 1, count: 2 };
+        assert_eq!(sent(&rx), vec![(1, request.clone()), (2, request)]);
+        assert_eq!(sync.peer_count(), 1);
+    }
+
+    #[test]
+    fn forked_peer_is_synced_from_an_earlier_height() {
+        let (a, b) = (H256::from(1), H256::from(2));
+        let config = test_config(&[a, b]);
+        let source = test_chain(config.clone());
+        mine(&source, &config, b, 4, Vec::new());
+        let node_config = test_config(&[a, b]);
+        let node = test_chain(node_config.clone());
+        mine(&node, &node_config, a, 2, Vec::new());
+
+        let (tx, rx) = channel();
+        let mut sync = Synchronizer::new(node.clone(), tx);
+        sync.start(1, 4);
+        sync.on_headers(1, source.get_headers(3, 2));
+        let expected = vec![
+            (1, MsgClass::GETHEADERS { from_height: 3, count: 2 }),
+            (1, MsgClass::GETHEADERS { from_height: 1, count: 4 }),
+        ];
+        assert_eq!(sent(&rx), expected);
+        assert_eq!(sync.peer_count(), 1);
+
+        sync.on_headers(1, source.get_headers(1, 4));
+        assert_eq!(sent(&rx), vec![(1, MsgClass::GETBODIES(hashes(&source, 1, 4)))]);
+        sync.on_bodies(1, source.get_bodies(&hashes(&source, 1, 4)));
+        assert_eq!(node.get_status(), source.get_status());
+    }
+
+    #[test]
+    fn headers_are_checked_with_their_epoch_validators() {
+        let (a, b) = (H256::from(1), H256::from(2));
+        let config = test_config(&[a]);
+        let source = test_chain(config.clone());
+        let mut change = ValidatorChange::new(true, Validator::from(keygroup(&b)), 1);
+        change.sign(&a);
+        let mut tx = SignedTransaction::new(1);
+        tx.set_data(change.to_data());
+        tx.signature = sign(&a, &tx.hash()).unwrap().into();
+        // b joins at the second epoch, from height 5.
+        mine(&source, &config, a, 4, vec![tx]);
+        mine(&source, &config, b, 3, Vec::new());
+
+        let (tx, rx) = channel();
+        let node = test_chain(test_config(&[a]));
+        let mut sync = Synchronizer::new(node.clone(), tx);
+        sync.start(1, 7);
+        sync.on_headers(1, source.get_headers(1, 7));
+        let expected = vec![
+            (1, MsgClass::GETHEADERS { from_height: 1, count: 7 }),
+            (1, MsgClass::GETBODIES(hashes(&source, 1, 4))),
+        ];
+        assert_eq!(sent(&rx), expected);
+
+        sync.on_bodies(1, source.get_bodies(&hashes(&source, 1, 4)));
+        assert_eq!(node.current_height(), 4);
+        assert_eq!(sent(&rx), vec![(1, MsgClass::GETHEADERS { from_height: 5, count: 3 })]);
+
+        sync.on_headers(1, source.get_headers(5, 3));
+        sync.on_bodies(1, source.get_bodies(&hashes(&source, 5, 3)));
+        assert_eq!(node.get_status(), source.get_status());
+        assert_eq!(sync.peer_count(), 1);
+    }
+}
@@ -0,0 +1,346 @@
+//! JSON-RPC over HTTP.
+//!
+//! Methods:
+//! - `sendTransaction(data)`: hex of an RLP encoded signed transaction, returns its hash.
+//! - `getBlockByNumber(number)`, `getBlockByHash(hash)`: block or null.
+//! - `getTransaction(hash)`: transaction with its position, or null.
+//! - `blockNumber()`: height of the best block.
+//! - `peerCount()`: number of known peers.
+//! - `getEquivocations()`: pairs of headers signed by one miner for the same timestamp.
+//! - `clockHealth()`: NTP offset, drift and sync health, null for other clocks.
+//!
+//! Events are pushed over WebSocket, see `pubsub`.
+
+extern crate jsonrpc_core;
+extern crate jsonrpc_http_server;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate rustc_hex;
+extern crate bincode;
+extern crate parking_lot;
+#[macro_use]
+extern crate log;
+extern crate util;
+extern crate chain;
+extern crate network;
+extern crate tx_pool;
+extern crate rlp;
+extern crate ws;
+#[cfg(test)]
+extern crate kvdb;
+#[cfg(test)]
+extern crate crypto;
+#[cfg(test)]
+extern crate bls;
+
+pub mod types;
+pub mod pubsub;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use bincode::{serialize, Infinite};
+use jsonrpc_core::{IoHandler, Params, Value, Error as RpcError, ErrorCode};
+use jsonrpc_http_server::{Server, ServerBuilder};
+use parking_lot::{Mutex, RwLock};
+use rustc_hex::FromHex;
+use chain::chain::Chain;
+use chain::transaction::SignedTransaction;
+use network::connection::Operation;
+use network::msgclass::MsgClass;
+use network::sync::Synchronizer;
+use tx_pool::Pool;
+use util::config::SleepyConfig;
+use util::clock::Clock;
+use util::hash::H256;
+use types::{RpcBlock, RpcTransaction};
+
+pub use pubsub::{start_ws, Hub, Topic};
+
+/// What the RPC methods work on.
+#[derive(Clone)]
+pub struct RpcContext {
+    pub chain: Arc<Chain>,
+    pub tx_pool: Arc<RwLock<Pool>>,
+    pub sync: Arc<Mutex<Synchronizer>>,
+    pub sender: Sender<(u32, Operation, Vec<u8>)>,
+}
+
+fn server_error<E: ::std::fmt::Debug>(e: E) -> RpcError {
+    RpcError {
+        code: ErrorCode::ServerError(-32000),
+        message: format!("{:?}", e),
+        data: None,
+    }
+}
+
+fn to_value<T: serde::Serialize>(v: T) -> Result<Value, RpcError> {
+    serde_json::to_value(v).map_err(server_error)
+}
+
+/// Decode and check a transaction, then queue and broadcast it.
+fn send_transaction(ctx: &RpcContext, data: &str) -> Result<H256, RpcError> {
+    let raw: Vec<u8> = data.trim_left_matches("0x").from_hex().map_err(|_| RpcError::invalid_params("invalid hex"))?;
+    let stx: SignedTransaction = rlp::UntrustedRlp::new(&raw).as_val().map_err(|_| RpcError::invalid_params("invalid transaction rlp"))?;
+    if stx.transaction.cal_hash() != stx.hash() {
+        return Err(RpcError::invalid_params("transaction hash mismatch"));
+    }
+    ctx.chain.tx_basic_check(&stx).map_err(server_error)?;
+
+    let hash = stx.hash();
+    let queued = { ctx.tx_pool.write().enqueue(stx.clone(), hash) };
+    if queued {
+        let message = serialize(&MsgClass::TX(stx), Infinite).unwrap();
+        ctx.sender.send((0, Operation::BROADCAST, message)).map_err(server_error)?;
+    }
+    Ok(hash)
+}
+
+fn get_transaction(ctx: &RpcContext, hash: &H256) -> Option<RpcTransaction> {
+    let addr = ctx.chain.get_transaction_address(hash)?;
+    let block = ctx.chain.get_block_by_hash(&addr.block_hash)?;
+    let tx = block.body.transactions.get(addr.index)?.clone();
+    Some(RpcTransaction::new(&tx, &block, addr.index))
+}
+
+/// Build the method table.
+pub fn io_handler(ctx: RpcContext) -> IoHandler {
+    let mut io = IoHandler::new();
+
+    let c = ctx.clone();
+    io.add_method("sendTransaction", move |params: Params| {
+        let (data,): (String,) = params.parse()?;
+        to_value(send_transaction(&c, &data)?)
+    });
+
+    let c = ctx.clone();
+    io.add_method("getBlockByNumber", move |params: Params| {
+        let (number,): (u64,) = params.parse()?;
+        let block = c.chain.block_hash_by_number(number).and_then(|h| c.chain.get_block_by_hash(&h));
+        to_value(block.map(RpcBlock::from))
+    });
+
+    let c = ctx.clone();
+    io.add_method("getBlockByHash", move |params: Params| {
+        let (hash,): (H256,) = params.parse()?;
+        to_value(c.chain.get_block_by_hash(&hash).map(RpcBlock::from))
+    });
+
+    let c = ctx.clone();
+    io.add_method("getTransaction", move |params: Params| {
+        let (hash,): (H256,) = params.parse()?;
+        to_value(get_transaction(&c, &hash))
+    });
+
+    let c = ctx.clone();
+    io.add_method("blockNumber", move |_: Params| {
+        Ok(Value::from(c.chain.current_height()))
+    });
+
+    let c = ctx.clone();
+    io.add_method("peerCount", move |_: Params| {
+        Ok(Value::from(c.sync.lock().peer_count()))
+    });
+
+    let c = ctx.clone();
+    io.add_method("getEquivocations", move |_: Params| {
+        to_value(c.chain.equivocations())
+    });
+
+    let c = ctx;
+    io.add_method("clockHealth", move |_: Params| {
+        to_value(c.chain.clock().health())
+    });
+
+    io
+}
+
+/// Start the server if enabled in the config, it stops when the returned value is dropped.
+pub fn start_rpc(config: &SleepyConfig, ctx: RpcContext) -> Option<Server> {
+    if !config.rpc.enable {
+        return None;
+    }
+    let addr = format!("{}:{}", config.rpc.listen_address, config.rpc.port);
+    let addr = addr.parse::<SocketAddr>().expect("invalid rpc address");
+    info!("start rpc server on {:?}!", addr);
+    let server = ServerBuilder::new(io_handler(ctx)).start_http(&addr).expect("start rpc server failed");
+    Some(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::mpsc::{channel, Receiver};
+    use rustc_hex::ToHex;
+    use kvdb::in_memory;
+    use crypto::{sign, KeyPair};
+    use chain::block::Block;
+    use chain::db;
+    use util::clock::{NtpClock, SystemClock};
+    use util::config::{Config, KeyGroup, ForkChoiceRule, BlockBufferConfig, RpcConfig, ClockSource, ByzantineConfig};
+
+    const SIGNER: &'static str = "40f2d8f8e1594579824fd04edfc7ff1ddffd6be153b23f4318e1acff037d3ea9";
+
+    /// A single miner winning every slot.
+    pub fn test_config() -> Arc<RwLock<SleepyConfig>> {
+        let (private_key, public_key, g) = bls::key_gen();
+        let signer = H256::from(SIGNER);
+        let config = Config {
+            id_card: 0,
+            port: 40000,
+            max_peer: 0,
+            steps: 1,
+            nps: 1,
+            miner_private_key: private_key,
+            signer_private_key: signer,
+            peers: Vec::new(),
+            keygroups: vec![KeyGroup {
+                proof_public_key: public_key,
+                proof_public_g: g,
+                signer_public_key: *KeyPair::from_privkey(signer).unwrap().pubkey(),
+            }],
+            epoch_len: 5,
+            start_time: 0,
+            ntp_servers: Vec::new(),
+            buffer_size: 5,
+            fork_choice: ForkChoiceRule::default(),
+            finality_depth: 2,
+            block_buffer: BlockBufferConfig::default(),
+            rpc: RpcConfig::default(),
+            clock: ClockSource::System,
+            byzantine: ByzantineConfig::default(),
+        };
+        Arc::new(RwLock::new(SleepyConfig { config: config, public_keys: HashMap::new() }))
+    }
+
+    pub fn test_chain(config: &Arc<RwLock<SleepyConfig>>, clock: Arc<Clock>) -> Arc<Chain> {
+        let consensus = Box::new(chain::consensus::BlsLottery::new(config.clone()));
+        Chain::init_with(config.clone(), Arc::new(in_memory(db::NUM_COLUMNS.unwrap())), consensus, clock).unwrap()
+    }
+
+    fn context(chain: Arc<Chain>) -> (RpcContext, Receiver<(u32, Operation, Vec<u8>)>) {
+        let (sender, receiver) = channel();
+        let ctx = RpcContext {
+            chain: chain.clone(),
+            tx_pool: Arc::new(RwLock::new(Pool::new(1000, 300))),
+            sync: Arc::new(Mutex::new(Synchronizer::new(chain, sender.clone()))),
+            sender: sender,
+        };
+        (ctx, receiver)
+    }
+
+    /// Mine a block at `t` on `parent`.
+    pub fn mine(chain: &Chain, parent: (u64, H256), t: u64, txs: Vec<SignedTransaction>) -> Block {
+        let seed = chain.anc_hash(parent.0, parent.1).unwrap();
+        let proof = chain.consensus().gen_proof(t, parent.0 + 1, seed);
+        chain.gen_block(parent.0, parent.1, t, proof, txs)
+    }
+
+    pub fn signed_tx(t: u64) -> SignedTransaction {
+        let mut tx = SignedTransaction::new(t);
+        tx.signature = sign(&H256::from(SIGNER), &tx.hash()).unwrap().into();
+        tx
+    }
+
+    /// Call `method` and get the `result` or `error` of the response.
+    fn call(io: &IoHandler, method: &str, params: &str) -> Value {
+        let request = format!(r#"{{"jsonrpc": "2.0", "method": "{}", "params": {}, "id": 1}}"#, method, params);
+        let response: Value = serde_json::from_str(&io.handle_request_sync(&request).unwrap()).unwrap();
+        match response.get("result") {
+            Some(result) => result.clone(),
+            None => response["error"].clone(),
+        }
+    }
+
+    fn json<T: serde::Serialize>(v: T) -> Value {
+        serde_json::to_value(v).unwrap()
+    }
+
+    #[test]
+    fn query_methods() {
+        let config = test_config();
+        let chain = test_chain(&config, Arc::new(SystemClock::new(1)));
+        let tx = signed_tx(1);
+        let block = mine(&chain, chain.get_status(), 1, vec![tx.clone()]);
+        let (ctx, _receiver) = context(chain.clone());
+        ctx.sync.lock().add_peer(3);
+        let io = io_handler(ctx);
+
+        assert_eq!(call(&io, "blockNumber", "[]"), json(1));
+        assert_eq!(call(&io, "peerCount", "[]"), json(1));
+        let by_number = call(&io, "getBlockByNumber", "[1]");
+        assert_eq!(by_number["hash"], json(block.hash()));
+        assert_eq!(by_number["transactions"], json(vec![tx.hash()]));
+        assert_eq!(call(&io, "getBlockByHash", &format!("[{}]", json(block.hash()))), by_number);
+        assert_eq!(call(&io, "getBlockByNumber", "[2]"), Value::Null);
+
+        let found = call(&io, "getTransaction", &format!("[{}]", json(tx.hash())));
+        assert_eq!(found["blockHash"], json(block.hash()));
+        assert_eq!(found["blockNumber"], json(1));
+        assert_eq!(found["index"], json(0));
+        assert_eq!(call(&io, "getTransaction", &format!("[{}]", json(H256::from(1)))), Value::Null);
+        assert_eq!(call(&io, "getBlockByNumber", "[\"one\"]")["code"], json(-32602));
+    }
+
+    #[test]
+    fn send_transaction_queues_and_broadcasts() {
+        let config = test_config();
+        let chain = test_chain(&config, Arc::new(SystemClock::new(1)));
+        let (ctx, receiver) = context(chain);
+        let pool = ctx.tx_pool.clone();
+        let io = io_handler(ctx);
+
+        let tx = signed_tx(1);
+        let data = format!("[\"0x{}\"]", rlp::encode(&tx).to_hex());
+        assert_eq!(call(&io, "sendTransaction", &data), json(tx.hash()));
+        assert_eq!(pool.read().len(), 1);
+        let (_, op, _) = receiver.try_recv().unwrap();
+        assert_eq!(op, Operation::BROADCAST);
+
+        let mut stale = signed_tx(2);
+        stale.transaction.timestamp = 3;
+        let data = format!("[\"0x{}\"]", rlp::encode(&stale).to_hex());
+        // invalid params.
+        assert_eq!(call(&io, "sendTransaction", &data)["code"], json(-32602));
+        assert_eq!(pool.read().len(), 1);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn equivocations_are_listed() {
+        let config = test_config();
+        let miner = test_chain(&config, Arc::new(SystemClock::new(1)));
+        let genesis = miner.get_status();
+        let a = mine(&miner, genesis, 1, Vec::new());
+        let b = mine(&miner, genesis, 1, vec![signed_tx(1)]);
+
+        let node = test_chain(&config, Arc::new(SystemClock::new(1)));
+        let io = io_handler(context(node.clone()).0);
+        assert_eq!(call(&io, "getEquivocations", "[]"), json(Vec::<H256>::new()));
+        node.insert_from(a.clone(), None).unwrap();
+        node.insert_from(b.clone(), None).unwrap();
+
+        let evidence = call(&io, "getEquivocations", "[]");
+        assert_eq!(evidence, json(node.equivocations()));
+        assert_eq!(evidence[0]["timestamp"], json(1));
+        assert_eq!(evidence[0]["first"]["hash"], json(a.hash()));
+        assert_eq!(evidence[0]["second"]["hash"], json(b.hash()));
+    }
+
+    #[test]
+    fn clock_health() {
+        let config = test_config();
+        let system = test_chain(&config, Arc::new(SystemClock::new(1)));
+        assert_eq!(call(&io_handler(context(system).0), "clockHealth", "[]"), Value::Null);
+
+        // no servers, never synced.
+        let ntp = test_chain(&config, NtpClock::start(Vec::new(), 1));
+        let health = call(&io_handler(context(ntp).0), "clockHealth", "[]");
+        assert_eq!(health["servers"], json(0));
+        assert_eq!(health["offset"], Value::Null);
+        assert_eq!(health["healthy"], json(false));
+    }
+}
@@ -0,0 +1,272 @@
+//! WebSocket push.
+//!
+//! A client sends `{"subscribe": "<topic>"}` or `{"unsubscribe": "<topic>"}`
+//! and then gets `{"topic": "<topic>", "result": <payload>}` for each event.
+//!
+//! Topics and payloads:
+//! - `newHeads`: `Header` of the new best block.
+//! - `reorgs`: `{"retracted": [hash], "enacted": [Header]}`.
+//! - `pendingTransactions`: `SignedTransaction` queued in the pool.
+//! - `minedBlocks`: `Header` of a block we generated.
+//!
+//! The server is enabled by `rpc.ws_enable`, apart from the HTTP server.
+
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use parking_lot::Mutex;
+use serde::Serialize;
+use ws::{self, CloseCode, Handler, Message};
+use chain::block::{Block, Header};
+use chain::chain::Chain;
+use chain::event::ChainEvent;
+use chain::transaction::SignedTransaction;
+use util::config::SleepyConfig;
+use util::hash::H256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Topic {
+    NewHeads,
+    Reorgs,
+    PendingTransactions,
+    MinedBlocks,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum Request {
+    Subscribe(Topic),
+    Unsubscribe(Topic),
+}
+
+#[derive(Serialize)]
+struct Notification<'a, T: 'a> {
+    topic: Topic,
+    result: &'a T,
+}
+
+#[derive(Serialize)]
+struct Reorg {
+    retracted: Vec<H256>,
+    enacted: Vec<Header>,
+}
+
+/// Subscribed connections by topic.
+#[derive(Default, Clone)]
+pub struct Hub {
+    subscribers: Arc<Mutex<Vec<(Topic, ws::Sender)>>>,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Hub::default()
+    }
+
+    fn subscribe(&self, topic: Topic, out: &ws::Sender) {
+        let mut subscribers = self.subscribers.lock();
+        let id = out.connection_id();
+        if !subscribers.iter().any(|&(t, ref s)| t == topic && s.connection_id() == id) {
+            subscribers.push((topic, out.clone()));
+        }
+    }
+
+    fn unsubscribe(&self, topic: Topic, id: u32) {
+        self.subscribers.lock().retain(|&(t, ref s)| t != topic || s.connection_id() != id);
+    }
+
+    fn remove(&self, id: u32) {
+        self.subscribers.lock().retain(|&(_, ref s)| s.connection_id() != id);
+    }
+
+    /// Send the payload to the subscribers of the topic.
+    pub fn publish<T: Serialize>(&self, topic: Topic, payload: &T) {
+        let text = match ::serde_json::to_string(&Notification { topic: topic, result: payload }) {
+            Ok(text) => text,
+            Err(e) => {
+                warn!("encode {:?} notification failed: {:?}", topic, e);
+                return;
+            }
+        };
+        self.subscribers.lock().retain(|&(t, ref s)| t != topic || s.send(text.as_str()).is_ok());
+    }
+}
+
+struct Connection {
+    out: ws::Sender,
+    hub: Hub,
+}
+
+impl Handler for Connection {
+    fn on_message(&mut self, msg: Message) -> ws::Result<()> {
+        let request = msg.as_text().ok().and_then(|t| ::serde_json::from_str::<Request>(t).ok());
+        match request {
+            Some(Request::Subscribe(topic)) => self.hub.subscribe(topic, &self.out),
+            Some(Request::Unsubscribe(topic)) => self.hub.unsubscribe(topic, self.out.connection_id()),
+            None => self.out.send(r#"{"error": "invalid request"}"#)?,
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        self.hub.remove(self.out.connection_id());
+    }
+}
+
+/// Header with its hash cache filled, so the hash is part of the payload.
+fn hashed(header: Header) -> Header {
+    header.hash();
+    header
+}
+
+fn header_of(chain: &Chain, hash: &H256) -> Option<Header> {
+    chain.get_block_header_by_hash(hash).map(|h| hashed(h.header))
+}
+
+/// Forward chain events, pool transactions and mined blocks to the hub.
+fn feed(hub: &Hub, chain: Arc<Chain>, pending: Receiver<SignedTransaction>, mined: Receiver<Block>) {
+    let events = chain.subscribe();
+    let h = hub.clone();
+    thread::spawn(move || for event in events {
+        match event {
+            ChainEvent::NewHead { hash, .. } => {
+                if let Some(header) = header_of(&chain, &hash) {
+                    h.publish(Topic::NewHeads, &header);
+                }
+            }
+            ChainEvent::Reorg { retracted, enacted } => {
+                let enacted = enacted.iter().filter_map(|hash| header_of(&chain, hash)).collect();
+                h.publish(Topic::Reorgs, &Reorg { retracted: retracted, enacted: enacted });
+            }
+            _ => {}
+        }
+    });
+
+    let h = hub.clone();
+    thread::spawn(move || for tx in pending {
+        h.publish(Topic::PendingTransactions, &tx);
+    });
+
+    let h = hub.clone();
+    thread::spawn(move || for block in mined {
+        h.publish(Topic::MinedBlocks, &hashed(block.header));
+    });
+}
+
+/// Start the WebSocket server if `rpc.ws_enable` is set in the config.
+pub fn start_ws(config: &SleepyConfig,
+                chain: Arc<Chain>,
+                pending: Receiver<SignedTransaction>,
+                mined: Receiver<Block>)
+                -> Option<Hub> {
+    if !config.rpc.ws_enable {
+        return None;
+    }
+    let hub = Hub::new();
+    feed(&hub, chain, pending, mined);
+
+    let addr = format!("{}:{}", config.rpc.listen_address, config.rpc.ws_port);
+    let h = hub.clone();
+    info!("start ws server on {}!", addr);
+    thread::spawn(move || if let Err(e) = ws::listen(addr.as_str(), |out| Connection { out: out, hub: h.clone() }) {
+        error!("ws server stopped: {:?}", e);
+    });
+    Some(hub)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::sync::mpsc::{channel, RecvTimeoutError};
+    use std::time::Duration;
+    use serde_json::{self, Value};
+    use util::clock::SystemClock;
+    use tests::{mine, signed_tx, test_chain, test_config};
+
+    /// Connect a client, returns its sender and the texts it receives.
+    fn client(port: u64) -> (ws::Sender, Receiver<String>) {
+        let (sender, receiver) = channel();
+        let (out_sender, out_receiver) = channel();
+        thread::spawn(move || {
+            ws::connect(format!("ws://127.0.0.1:{}", port), |out: ws::Sender| {
+                out_sender.send(out).unwrap();
+                let sender = sender.clone();
+                move |msg: Message| -> ws::Result<()> {
+                    sender.send(msg.into_text()?).unwrap();
+                    Ok(())
+                }
+            }).unwrap();
+        });
+        (out_receiver.recv().unwrap(), receiver)
+    }
+
+    fn next(receiver: &Receiver<String>) -> Value {
+        serde_json::from_str(&receiver.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap()
+    }
+
+    fn wait_for(hub: &Hub, subscriptions: usize) {
+        for _ in 0..500 {
+            if hub.subscribers.lock().len() == subscriptions {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("expected {} subscriptions", subscriptions);
+    }
+
+    #[test]
+    fn subscribe_notify_unsubscribe() {
+        let config = test_config();
+        let port = 42338;
+        {
+            let mut config = config.write();
+            config.rpc.ws_port = port;
+            config.rpc.enable = true;
+        }
+        let chain = test_chain(&config, Arc::new(SystemClock::new(1)));
+        let (_pending, pending_receiver) = channel();
+        let (_mined, mined_receiver) = channel();
+        // rpc alone does not start it.
+        assert!(start_ws(&config.read(), chain.clone(), pending_receiver, mined_receiver).is_none());
+
+        config.write().rpc.ws_enable = true;
+        let (pending_sender, pending_receiver) = channel();
+        let (_mined, mined_receiver) = channel();
+        let hub = start_ws(&config.read(), chain.clone(), pending_receiver, mined_receiver).unwrap();
+        for _ in 0..500 {
+            if TcpStream::connect(("127.0.0.1", port as u16)).is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let (out, receiver) = client(port);
+        out.send(r#"{"subscribe": "newHeads"}"#).unwrap();
+        out.send(r#"{"subscribe": "pendingTransactions"}"#).unwrap();
+        out.send(r#"{"subscribe": "newHeads"}"#).unwrap();
+        wait_for(&hub, 2);
+
+        let block = mine(&chain, chain.get_status(), 1, Vec::new());
+        let head = next(&receiver);
+        assert_eq!(head["topic"], Value::from("newHeads"));
+        assert_eq!(head["result"]["hash"], serde_json::to_value(block.hash()).unwrap());
+
+        let tx = signed_tx(2);
+        pending_sender.send(tx.clone()).unwrap();
+        let queued = next(&receiver);
+        assert_eq!(queued["topic"], Value::from("pendingTransactions"));
+        assert_eq!(queued["result"]["hash"], serde_json::to_value(tx.hash()).unwrap());
+
+        out.send("hello").unwrap();
+        assert_eq!(next(&receiver)["error"], Value::from("invalid request"));
+
+        out.send(r#"{"unsubscribe": "newHeads"}"#).unwrap();
+        wait_for(&hub, 1);
+        mine(&chain, chain.get_status(), 3, Vec::new());
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(500)), Err(RecvTimeoutError::Timeout));
+
+        out.close(CloseCode::Normal).unwrap();
+        wait_for(&hub, 0);
+    }
+}
@@ -0,0 +1,59 @@
+use rustc_hex::ToHex;
+use chain::block::Block;
+use chain::transaction::SignedTransaction;
+use util::hash::H256;
+
+/// Block as returned by `getBlockByNumber` and `getBlockByHash`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcBlock {
+    pub hash: H256,
+    pub height: u64,
+    pub parent_hash: H256,
+    pub timestamp: u64,
+    pub transactions_root: H256,
+    pub state_root: H256,
+    pub receipts_root: H256,
+    pub transactions: Vec<H256>,
+}
+
+impl From<Block> for RpcBlock {
+    fn from(block: Block) -> Self {
+        RpcBlock {
+            hash: block.hash(),
+            height: block.height,
+            parent_hash: block.parent_hash,
+            timestamp: block.timestamp,
+            transactions_root: block.transactions_root,
+            state_root: block.state_root,
+            receipts_root: block.receipts_root,
+            transactions: block.body.transactions.iter().map(|tx| tx.hash()).collect(),
+        }
+    }
+}
+
+/// Transaction as returned by `getTransaction`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcTransaction {
+    pub hash: H256,
+    /// Hex encoded data.
+    pub data: String,
+    pub timestamp: u64,
+    pub block_hash: H256,
+    pub block_number: u64,
+    pub index: usize,
+}
+
+impl RpcTransaction {
+    pub fn new(tx: &SignedTransaction, block: &Block, index: usize) -> Self {
+        RpcTransaction {
+            hash: tx.hash(),
+            data: format!("0x{}", tx.data.to_hex()),
+            timestamp: tx.timestamp,
+            block_hash: block.hash(),
+            block_number: block.height,
+            index: index,
+        }
+    }
+}
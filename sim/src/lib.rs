@@ -0,0 +1,293 @@
+//! In-process multi-node simulation.
+//!
+//! Runs N nodes in one process over `kvdb::in_memory`, a shared mock clock
+//! and an in-memory router with configurable latency, loss and partitions.
+//! Each `step` moves the clock one tick, lets every node try to mine, and
+//! delivers the messages due.
+
+extern crate rand;
+extern crate bincode;
+extern crate parking_lot;
+#[macro_use]
+extern crate log;
+extern crate util;
+extern crate chain;
+extern crate network;
+extern crate tx_pool;
+extern crate bls;
+extern crate kvdb;
+extern crate crypto;
+extern crate miner;
+
+pub mod router;
+pub mod node;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use util::{H256, H512};
+use util::clock::{Clock, MockClock};
+use util::config::{Config, SleepyConfig, KeyGroup, ForkChoiceRule, BlockBufferConfig, RpcConfig, ClockSource, ByzantineConfig};
+use crypto::KeyPair;
+use chain::validator::Validator;
+
+pub use router::{Router, RouterConfig, RouterStats};
+pub use node::Node;
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub nodes: usize,
+    /// Ticks per second.
+    pub nps: u64,
+    pub steps: u64,
+    pub epoch_len: u64,
+    pub finality_depth: u64,
+    pub fork_choice: ForkChoiceRule,
+    pub router: RouterConfig,
+    /// Seed of the router randomness.
+    pub seed: u32,
+    /// Misbehaving nodes, the others are honest.
+    pub byzantine: Vec<(usize, ByzantineConfig)>,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            nodes: 4,
+            nps: 10,
+            steps: 1,
+            epoch_len: 10,
+            finality_depth: 20,
+            fork_choice: ForkChoiceRule::default(),
+            router: RouterConfig::default(),
+            seed: 1,
+            byzantine: Vec::new(),
+        }
+    }
+}
+
+/// Keys of a simulated miner.
+struct Keys {
+    miner_private_key: Vec<u8>,
+    signer_private_key: H256,
+    validator: Validator,
+}
+
+fn gen_keys(i: usize) -> Keys {
+    let (private_key, public_key, g) = bls::key_gen();
+    let signer_private_key = H256::from((i + 1) as u64);
+    let signer_public_key = *KeyPair::from_privkey(signer_private_key).expect("valid signer key").pubkey();
+    Keys {
+        miner_private_key: private_key,
+        signer_private_key: signer_private_key,
+        validator: Validator {
+            proof_public_key: public_key,
+            proof_public_g: g,
+            signer_public_key: signer_public_key,
+        },
+    }
+}
+
+fn node_config(id: usize, config: &SimConfig, keys: &[Keys]) -> SleepyConfig {
+    let keygroups: Vec<KeyGroup> = keys.iter().map(|k| KeyGroup {
+        proof_public_key: k.validator.proof_public_key.clone(),
+        proof_public_g: k.validator.proof_public_g.clone(),
+        signer_public_key: k.validator.signer_public_key,
+    }).collect();
+    let public_keys: HashMap<H512, (Vec<u8>, Vec<u8>)> = keygroups.iter()
+        .map(|k| (k.signer_public_key, (k.proof_public_key.clone(), k.proof_public_g.clone())))
+        .collect();
+    let c = Config {
+        id_card: id as u32,
+        port: 0,
+        max_peer: (config.nodes - 1) as u64,
+        steps: config.steps,
+        nps: config.nps,
+        miner_private_key: keys[id].miner_private_key.clone(),
+        signer_private_key: keys[id].signer_private_key,
+        peers: Vec::new(),
+        keygroups: keygroups,
+        epoch_len: config.epoch_len,
+        start_time: 0,
+        ntp_servers: Vec::new(),
+        buffer_size: 5,
+        fork_choice: config.fork_choice,
+        finality_depth: config.finality_depth,
+        block_buffer: BlockBufferConfig::default(),
+        rpc: RpcConfig::default(),
+        clock: ClockSource::System,
+        byzantine: config.byzantine.iter()
+            .find(|b| b.0 == id)
+            .map_or(ByzantineConfig::default(), |b| b.1.clone()),
+    };
+    SleepyConfig { config: c, public_keys: public_keys }
+}
+
+pub struct Simulation {
+    pub clock: Arc<MockClock>,
+    pub nodes: Vec<Node>,
+    pub router: Router,
+    now: u64,
+}
+
+impl Simulation {
+    pub fn new(config: SimConfig) -> Self {
+        let keys: Vec<Keys> = (0..config.nodes).map(gen_keys).collect();
+        let clock = Arc::new(MockClock::new(0));
+        let nodes = (0..config.nodes).map(|i| {
+            Node::new(node_config(i, &config, &keys), clock.clone() as Arc<Clock>)
+        }).collect();
+        Simulation {
+            clock: clock,
+            nodes: nodes,
+            router: Router::new(config.nodes as u32, config.router.clone(), config.seed),
+            now: 0,
+        }
+    }
+
+    /// Current tick.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Hand the messages sent by the nodes to the router.
+    fn flush(&mut self) {
+        for node in &self.nodes {
+            for (origin, op, msg) in node.outgoing() {
+                self.router.send(node.id, origin, op, msg, self.now);
+            }
+        }
+    }
+
+    /// Move one tick, with or without mining.
+    fn advance(&mut self, mine: bool) {
+        self.now += 1;
+        self.clock.set(self.now);
+        if mine {
+            let now = self.now;
+            for node in self.nodes.iter_mut() {
+                node.mine(now);
+            }
+        }
+        self.flush();
+        for (to, from, msg) in self.router.deliver(self.now) {
+            self.nodes[to as usize].handle(from, &msg);
+        }
+        for node in self.nodes.iter_mut() {
+            node.tick();
+        }
+        self.flush();
+    }
+
+    pub fn step(&mut self) {
+        self.advance(true);
+    }
+
+    pub fn run(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.step();
+        }
+    }
+
+    /// Stop mining and deliver what is in flight, nodes exchange their status
+    /// so the ones left behind catch up.
+    pub fn settle(&mut self, ticks: u64) {
+        for node in &self.nodes {
+            node.send_status();
+        }
+        for _ in 0..ticks {
+            self.advance(false);
+        }
+    }
+
+    /// Highest height at which all given nodes have the same canonical block.
+    pub fn common_prefix(&self, nodes: &[usize]) -> u64 {
+        let mut height = nodes.iter().map(|i| self.nodes[*i].chain.current_height()).min().unwrap_or(0);
+        loop {
+            let mut hashes = nodes.iter().map(|i| self.nodes[*i].chain.block_hash_by_number(height));
+            let first = hashes.next().unwrap_or(None);
+            if height == 0 || hashes.all(|h| h == first) {
+                return height;
+            }
+            height -= 1;
+        }
+    }
+
+    /// Whether all given nodes have the same best block.
+    pub fn converged(&self, nodes: &[usize]) -> bool {
+        let mut heads = nodes.iter().map(|i| self.nodes[*i].get_status());
+        let first = heads.next();
+        heads.all(|h| Some(h) == first)
+    }
+
+    pub fn all(&self) -> Vec<usize> {
+        (0..self.nodes.len()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::config::Behaviour;
+    use chain::verify::verify_chain;
+
+    #[test]
+    fn honest_nodes_converge() {
+        let mut sim = Simulation::new(SimConfig {
+            router: RouterConfig { min_latency: 1, max_latency: 3, loss: 0.0 },
+            ..SimConfig::default()
+        });
+        sim.run(300);
+        sim.settle(20);
+        let all = sim.all();
+        assert!(sim.nodes[0].chain.current_height() > 5);
+        assert!(sim.common_prefix(&all) + 3 >= sim.nodes[0].chain.current_height());
+
+        for node in &sim.nodes {
+            let report = verify_chain(&node.chain, &node.config.read());
+            assert_eq!(report.invalid, None);
+            assert_eq!(report.blocks, node.chain.current_height() + 1);
+        }
+    }
+
+    #[test]
+    fn partitions_heal() {
+        let mut sim = Simulation::new(SimConfig::default());
+        sim.router.partition(&[&[0, 1], &[2, 3]]);
+        sim.run(200);
+        assert!(!sim.converged(&[0, 2]));
+        sim.router.heal();
+        sim.run(100);
+        sim.settle(30);
+        assert!(sim.converged(&sim.all()));
+    }
+
+    fn byzantine(behaviour: Behaviour) -> Simulation {
+        Simulation::new(SimConfig {
+            byzantine: vec![(3, ByzantineConfig { behaviour: behaviour, ..ByzantineConfig::default() })],
+            ..SimConfig::default()
+        })
+    }
+
+    #[test]
+    fn honest_nodes_survive_attacks() {
+        let honest = [0, 1, 2];
+        for behaviour in vec![Behaviour::Withhold, Behaviour::StaleParent, Behaviour::FutureTimestamp, Behaviour::OrphanFlood] {
+            let mut sim = byzantine(behaviour);
+            sim.run(300);
+            sim.settle(20);
+            let height = sim.nodes[0].chain.current_height();
+            assert!(height > 5, "{:?}", behaviour);
+            assert!(sim.common_prefix(&honest) + 3 >= height, "{:?}", behaviour);
+        }
+    }
+
+    #[test]
+    fn equivocation_is_detected() {
+        let mut sim = byzantine(Behaviour::Equivocate);
+        sim.run(300);
+        sim.settle(20);
+        let evidence = sim.nodes[0].chain.equivocations();
+        assert!(!evidence.is_empty());
+        assert!(evidence.iter().all(|e| e.signer == sim.nodes[3].config.read().keygroups[3].signer_public_key));
+    }
+}
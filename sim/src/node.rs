@@ -0,0 +1,111 @@
+//! A full node driven by the simulation instead of its own threads.
+//!
+//! Mining follows `miner::start_miner` and messages go through the
+//! `miner::handler::Handler` of the `sleepy` binary, one call per tick or
+//! message, including the strategy selected in the `byzantine` config.
+//! Buffered blocks are inserted on each tick too, the chain runs no threads.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use bincode::{serialize, Infinite};
+use parking_lot::{Mutex, RwLock};
+use kvdb::in_memory;
+use chain::chain::Chain;
+use chain::block::Block;
+use chain::consensus::BlsLottery;
+use chain::db;
+use miner::handler::Handler;
+use miner::strategy::{self, Strategy};
+use network::connection::Operation;
+use network::msgclass::MsgClass;
+use network::sync::Synchronizer;
+use tx_pool::Pool;
+use util::H256;
+use util::clock::Clock;
+use util::config::SleepyConfig;
+
+pub struct Node {
+    pub id: u32,
+    pub chain: Arc<Chain>,
+    pub tx_pool: Arc<RwLock<Pool>>,
+    pub config: Arc<RwLock<SleepyConfig>>,
+    sync: Arc<Mutex<Synchronizer>>,
+    strategy: Arc<Mutex<Box<Strategy>>>,
+    handler: Handler,
+    sender: Sender<(u32, Operation, Vec<u8>)>,
+    outbox: Receiver<(u32, Operation, Vec<u8>)>,
+    /// Last tick we tried to mine at.
+    time: u64,
+    /// Equivocations reported, by header hashes.
+    reported: HashSet<(H256, H256)>,
+}
+
+impl Node {
+    pub fn new(config: SleepyConfig, clock: Arc<Clock>) -> Self {
+        let id = config.get_id();
+        let strategy = strategy::from_config(&config.byzantine, config.get_signer_private_key());
+        let strategy = Arc::new(Mutex::new(strategy));
+        let config = Arc::new(RwLock::new(config));
+        let db = Arc::new(in_memory(db::NUM_COLUMNS.unwrap()));
+        let consensus = Box::new(BlsLottery::new(config.clone()));
+        let chain = Chain::init_driven(config.clone(), db, consensus, clock).expect("init chain failed");
+        let (sender, outbox) = channel();
+        let tx_pool = Arc::new(RwLock::new(Pool::new(1000, 300)));
+        let sync = Arc::new(Mutex::new(Synchronizer::new(chain.clone(), sender.clone())));
+        Node {
+            id: id,
+            handler: Handler::new(chain.clone(), tx_pool.clone(), sync.clone(), strategy.clone(), sender.clone()),
+            sync: sync,
+            strategy: strategy,
+            chain: chain,
+            tx_pool: tx_pool,
+            config: config,
+            sender: sender,
+            outbox: outbox,
+            time: 0,
+            reported: HashSet::new(),
+        }
+    }
+
+    pub fn get_status(&self) -> (u64, H256) {
+        self.chain.get_status()
+    }
+
+    fn send(&self, origin: u32, op: Operation, msg: &MsgClass) {
+        let message = serialize(msg, Infinite).unwrap();
+        self.sender.send((origin, op, message)).unwrap();
+    }
+
+    /// Mine at `now` as `miner::start_miner` does, returns the mined block.
+    pub fn mine(&mut self, now: u64) -> Option<Block> {
+        if now <= self.time {
+            return None;
+        }
+        self.time = now;
+        miner::mine(now, &self.chain, &self.config, &self.tx_pool, &self.strategy, &mut self.reported, &self.sender)
+    }
+
+    /// Handle a message from `origin`.
+    pub fn handle(&mut self, origin: u32, msg: &[u8]) {
+        self.handler.handle(origin, msg);
+    }
+
+    /// Retry timed out sync requests and insert the buffered blocks now
+    /// importable.
+    pub fn tick(&mut self) {
+        self.sync.lock().tick();
+        self.chain.handle_pending();
+        self.chain.handle_orphans();
+    }
+
+    /// Broadcast our chain status.
+    pub fn send_status(&self) {
+        self.send(self.id, Operation::BROADCAST, &MsgClass::STATUS(self.sync.lock().status()));
+    }
+
+    /// Take the messages sent since the last call, as given to the connection.
+    pub fn outgoing(&self) -> Vec<(u32, Operation, Vec<u8>)> {
+        self.outbox.try_iter().collect()
+    }
+}
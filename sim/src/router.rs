@@ -0,0 +1,181 @@
+//! In-memory message router replacing the TCP server and connections.
+//!
+//! Messages are delayed by a random latency counted in clock ticks, may be
+//! lost, and are dropped between nodes in different partitions.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use rand::{Rng, SeedableRng, XorShiftRng};
+use network::connection::{is_send, Operation};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouterConfig {
+    /// Latency range in ticks, both ends included.
+    pub min_latency: u64,
+    pub max_latency: u64,
+    /// Probability for a message to be lost.
+    pub loss: f64,
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        RouterConfig {
+            min_latency: 1,
+            max_latency: 1,
+            loss: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RouterStats {
+    pub sent: u64,
+    pub lost: u64,
+    pub partitioned: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Envelope {
+    at: u64,
+    seq: u64,
+    from: u32,
+    to: u32,
+    msg: Vec<u8>,
+}
+
+// reversed, so the heap pops the earliest message first.
+impl Ord for Envelope {
+    fn cmp(&self, other: &Envelope) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+impl PartialOrd for Envelope {
+    fn partial_cmp(&self, other: &Envelope) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+pub struct Router {
+    config: RouterConfig,
+    nodes: u32,
+    rng: XorShiftRng,
+    queue: BinaryHeap<Envelope>,
+    seq: u64,
+    /// Partition of each node, all in the same one when healed.
+    groups: Vec<usize>,
+    stats: RouterStats,
+}
+
+impl Router {
+    pub fn new(nodes: u32, config: RouterConfig, seed: u32) -> Self {
+        Router {
+            config: config,
+            nodes: nodes,
+            rng: XorShiftRng::from_seed([seed, 0x193a6754, 0xa8a7d469, 0x97830e05]),
+            queue: BinaryHeap::new(),
+            seq: 0,
+            groups: vec![0; nodes as usize],
+            stats: RouterStats::default(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: RouterConfig) {
+        self.config = config;
+    }
+
+    /// Split the nodes, the ones missing from `groups` are isolated.
+    pub fn partition(&mut self, groups: &[&[u32]]) {
+        let isolated = groups.len();
+        self.groups = (0..self.nodes as usize).map(|i| isolated + i).collect();
+        for (g, nodes) in groups.iter().enumerate() {
+            for n in nodes.iter() {
+                self.groups[*n as usize] = g;
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.groups = vec![0; self.nodes as usize];
+    }
+
+    pub fn connected(&self, a: u32, b: u32) -> bool {
+        self.groups[a as usize] == self.groups[b as usize]
+    }
+
+    pub fn stats(&self) -> RouterStats {
+        self.stats
+    }
+
+    /// Queue a message sent by `from` at `now`, `origin` and `op` as given to the connection.
+    pub fn send(&mut self, from: u32, origin: u32, op: Operation, msg: Vec<u8>, now: u64) {
+        for to in 0..self.nodes {
+            if to == from || !is_send(to, origin, op) {
+                continue;
+            }
+            self.stats.sent += 1;
+            if !self.connected(from, to) {
+                self.stats.partitioned += 1;
+                continue;
+            }
+            if self.config.loss > 0.0 && self.rng.next_f64() < self.config.loss {
+                self.stats.lost += 1;
+                continue;
+            }
+            let latency = self.rng.gen_range(self.config.min_latency, self.config.max_latency + 1);
+            self.seq += 1;
+            self.queue.push(Envelope {
+                at: now + latency,
+                seq: self.seq,
+                from: from,
+                to: to,
+                msg: msg.clone(),
+            });
+        }
+    }
+
+    /// Take the messages due at `now`, as (to, from, message).
+    pub fn deliver(&mut self, now: u64) -> Vec<(u32, u32, Vec<u8>)> {
+        let mut due = Vec::new();
+        loop {
+            match self.queue.peek() {
+                Some(e) if e.at <= now => {}
+                _ => break,
+            }
+            let e = self.queue.pop().expect("peeked above");
+            due.push((e.to, e.from, e.msg));
+        }
+        due
+    }
+
+    /// Number of messages in flight.
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delivers_in_order_within_partitions() {
+        let mut router = Router::new(3, RouterConfig { min_latency: 2, max_latency: 2, loss: 0.0 }, 1);
+        router.send(0, 0, Operation::BROADCAST, vec![1], 0);
+        router.send(1, 0, Operation::SINGLE, vec![2], 1);
+        assert!(router.deliver(1).is_empty());
+        assert_eq!(router.deliver(2), vec![(1, 0, vec![1]), (2, 0, vec![1])]);
+        assert_eq!(router.deliver(3), vec![(0, 1, vec![2])]);
+
+        router.partition(&[&[0, 1]]);
+        router.send(0, 0, Operation::BROADCAST, vec![3], 3);
+        assert_eq!(router.deliver(5), vec![(1, 0, vec![3])]);
+        assert_eq!(router.stats().partitioned, 1);
+
+        router.heal();
+        router.set_config(RouterConfig { min_latency: 1, max_latency: 1, loss: 1.0 });
+        router.send(2, 2, Operation::BROADCAST, vec![4], 5);
+        assert_eq!(router.pending(), 0);
+        assert_eq!(router.stats().lost, 2);
+    }
+}
@@ -4,6 +4,7 @@ use std::collections::BTreeSet;
 use chain::transaction::SignedTransaction;
 use util::hash::H256;
 use std::cmp::Ordering;
+use std::sync::mpsc::{channel, Receiver, Sender};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Strategy {
@@ -53,6 +54,8 @@ pub struct Pool {
     txs: HashMap<H256, SignedTransaction>,
     strategy: Strategy,
     order: u64,
+    /// Receive every newly queued transaction.
+    listeners: Vec<Sender<SignedTransaction>>,
 }
 
 impl Pool {
@@ -64,6 +67,7 @@ impl Pool {
             txs: HashMap::new(),
             strategy: Strategy::FIFO,
             order: 0,
+            listeners: Vec::new(),
         }
     }
 
@@ -75,6 +79,7 @@ impl Pool {
             txs: HashMap::new(),
             strategy: strategy,
             order: 0,
+            listeners: Vec::new(),
         }
     }
 
@@ -105,11 +110,19 @@ impl Pool {
             };
             let tx_order = TxOrder::new(hash, order);
             self.order_set.insert(tx_order);
+            self.listeners.retain(|l| l.send(tx.clone()).is_ok());
             self.txs.insert(hash, tx);
         }
         is_ok
     }
 
+    /// Get the transactions queued from now on.
+    pub fn subscribe(&mut self) -> Receiver<SignedTransaction> {
+        let (tx, rx) = channel();
+        self.listeners.push(tx);
+        rx
+    }
+
     fn update_order_set(&mut self, hash_list: &[H256]) {
         self.order_set = self.order_set
             .iter()
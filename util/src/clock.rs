@@ -0,0 +1,382 @@
+//! Time sources.
+//!
+//! Times are counted in ticks, `nps` ticks per second, like block timestamps.
+
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use parking_lot::Mutex;
+use config::{SleepyConfig, ClockSource};
+use time;
+use ntp;
+
+pub trait Clock: Send + Sync {
+    /// Current time in ticks, `None` if the source is unavailable.
+    fn now(&self) -> Option<u64>;
+
+    /// Synchronization state, for sources synchronized over the network.
+    fn health(&self) -> Option<SyncHealth> {
+        None
+    }
+}
+
+fn to_ticks(nanos: i64, nps: u64) -> u64 {
+    (nanos / (1000000000 / nps) as i64) as u64
+}
+
+/// Ask all servers at once, returns the first non-zero answer in nanoseconds or 0.
+pub fn ntp_timestamp(servers: &[String]) -> i64 {
+    let (tx, rx) = mpsc::channel();
+    for addr in servers.iter().cloned() {
+        let tx = tx.clone();
+
+        thread::spawn(move || {
+            let time = match ntp::request(addr) {
+                Ok(res) => {
+                    let t = time::Timespec::from(res.transmit_time);
+                    t.sec * 1000000000 + t.nsec as i64
+                },
+                _ => 0,
+            };
+            let _ = tx.send(time);
+        });
+    }
+
+    let mut r: i64 = 0;
+    for _ in 0..servers.len() {
+        if let Ok(t) = rx.recv() {
+            if t != 0 && r == 0 {
+                r = t
+            }
+        }
+    }
+    r
+}
+
+/// Seconds between two polls of the NTP servers.
+pub const POLL_INTERVAL: u64 = 64;
+/// Seconds to wait for the answers of one poll.
+const POLL_TIMEOUT: u64 = 5;
+/// Answers with a longer round trip are dropped, in nanoseconds.
+const MAX_DELAY: i64 = 1000000000;
+/// Offsets closer than this to the median are never outliers, in nanoseconds.
+const MIN_DEVIATION: i64 = 50000000;
+/// The clock is unhealthy when the last good poll is older than this many intervals.
+const STALE_POLLS: u64 = 3;
+
+fn nanos(t: time::Timespec) -> i64 {
+    t.sec * 1000000000 + t.nsec as i64
+}
+
+fn local_nanos() -> i64 {
+    nanos(time::get_time())
+}
+
+/// Offset of the server against the local clock, and round trip delay.
+///
+/// `t1` and `t4` are the local send and receive times, `t2` and `t3` the
+/// server receive and transmit times.
+fn offset_and_delay(t1: i64, t2: i64, t3: i64, t4: i64) -> (i64, i64) {
+    (((t2 - t1) + (t3 - t4)) / 2, (t4 - t1) - (t3 - t2))
+}
+
+fn median(values: &[i64]) -> Option<i64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort();
+    let mid = sorted.len() / 2;
+    match sorted.len() % 2 {
+        0 => Some((sorted[mid - 1] + sorted[mid]) / 2),
+        _ => Some(sorted[mid]),
+    }
+}
+
+/// Drop the offsets further from the median than three median absolute deviations.
+fn reject_outliers(offsets: &[i64]) -> Vec<i64> {
+    let m = match median(offsets) {
+        Some(m) => m,
+        None => return Vec::new(),
+    };
+    let deviations: Vec<i64> = offsets.iter().map(|o| (o - m).abs()).collect();
+    let limit = ::std::cmp::max(MIN_DEVIATION, 3 * median(&deviations).unwrap_or(0));
+    offsets.iter().cloned().filter(|o| (o - m).abs() <= limit).collect()
+}
+
+/// Ask one server, returns its offset against the local clock in nanoseconds.
+fn poll_server(addr: String) -> Option<i64> {
+    let t1 = local_nanos();
+    let packet = ntp::request(addr).ok()?;
+    let t4 = local_nanos();
+    let t2 = nanos(time::Timespec::from(packet.recv_time));
+    let t3 = nanos(time::Timespec::from(packet.transmit_time));
+    let (offset, delay) = offset_and_delay(t1, t2, t3, t4);
+    match delay >= 0 && delay <= MAX_DELAY {
+        true => Some(offset),
+        false => None,
+    }
+}
+
+/// State of the NTP synchronization.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SyncHealth {
+    /// Median offset of the local clock, in nanoseconds.
+    pub offset: Option<i64>,
+    /// Change of the offset per second of local time, in nanoseconds.
+    pub drift: Option<i64>,
+    /// Servers polled, answering and kept after outlier rejection in the last poll.
+    pub servers: usize,
+    pub answered: usize,
+    pub accepted: usize,
+    /// Seconds since the last poll with at least one accepted answer.
+    pub last_sync_age: Option<u64>,
+    pub healthy: bool,
+}
+
+#[derive(Default)]
+struct SyncState {
+    offset: Option<i64>,
+    drift: Option<i64>,
+    answered: usize,
+    accepted: usize,
+    /// Local time of the last good poll, in nanoseconds.
+    last_sync: Option<i64>,
+}
+
+/// Local clock corrected by the median offset of the NTP servers.
+///
+/// The servers are polled in a background thread every `POLL_INTERVAL`
+/// seconds, `now` never blocks on the network and is `None` until a poll
+/// succeeds.
+pub struct NtpClock {
+    servers: Vec<String>,
+    nps: u64,
+    state: Mutex<SyncState>,
+}
+
+impl NtpClock {
+    fn new(servers: Vec<String>, nps: u64) -> Self {
+        NtpClock {
+            servers: servers,
+            nps: nps,
+            state: Mutex::new(SyncState::default()),
+        }
+    }
+
+    /// Poll in the background until the clock is dropped, starting right
+    /// away without waiting for the first answers.
+    pub fn start(servers: Vec<String>, nps: u64) -> Arc<Self> {
+        let clock = Arc::new(NtpClock::new(servers, nps));
+        let weak = Arc::downgrade(&clock);
+        thread::spawn(move || loop {
+            match weak.upgrade() {
+                Some(clock) => clock.sync(),
+                None => break,
+            }
+            thread::sleep(Duration::from_secs(POLL_INTERVAL));
+        });
+        clock
+    }
+
+    /// Poll all servers and update the offset.
+    pub fn sync(&self) {
+        let (tx, rx) = mpsc::channel();
+        for addr in self.servers.iter().cloned() {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let _ = tx.send(poll_server(addr));
+            });
+        }
+
+        let deadline = time::get_time() + time::Duration::seconds(POLL_TIMEOUT as i64);
+        let mut offsets = Vec::new();
+        for _ in 0..self.servers.len() {
+            let left = deadline - time::get_time();
+            let left = match left.to_std() {
+                Ok(d) => d,
+                Err(_) => break,
+            };
+            match rx.recv_timeout(left) {
+                Ok(Some(offset)) => offsets.push(offset),
+                Ok(None) => {}
+                Err(_) => break,
+            }
+        }
+        self.update(&offsets, local_nanos());
+    }
+
+    fn update(&self, offsets: &[i64], local: i64) {
+        let accepted = reject_outliers(offsets);
+        let mut state = self.state.lock();
+        state.answered = offsets.len();
+        state.accepted = accepted.len();
+        let offset = match median(&accepted) {
+            Some(o) => o,
+            None => {
+                warn!("ntp sync failed, {} of {} servers answered", offsets.len(), self.servers.len());
+                return;
+            }
+        };
+        if let (Some(old), Some(last)) = (state.offset, state.last_sync) {
+            let elapsed = (local - last) / 1000000000;
+            if elapsed > 0 {
+                state.drift = Some((offset - old) / elapsed);
+            }
+        }
+        trace!("ntp offset {}ns from {} servers", offset, accepted.len());
+        state.offset = Some(offset);
+        state.last_sync = Some(local);
+    }
+}
+
+impl Clock for NtpClock {
+    fn now(&self) -> Option<u64> {
+        let offset = self.state.lock().offset?;
+        Some(to_ticks(local_nanos() + offset, self.nps))
+    }
+
+    fn health(&self) -> Option<SyncHealth> {
+        let state = self.state.lock();
+        let age = state.last_sync.map(|t| ((local_nanos() - t) / 1000000000) as u64);
+        Some(SyncHealth {
+            offset: state.offset,
+            drift: state.drift,
+            servers: self.servers.len(),
+            answered: state.answered,
+            accepted: state.accepted,
+            last_sync_age: age,
+            healthy: match age {
+                Some(age) => age <= STALE_POLLS * POLL_INTERVAL,
+                None => false,
+            },
+        })
+    }
+}
+
+/// The local clock, for offline testnets.
+pub struct SystemClock {
+    nps: u64,
+}
+
+impl SystemClock {
+    pub fn new(nps: u64) -> Self {
+        SystemClock { nps: nps }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Option<u64> {
+        let now = time::now().to_timespec();
+        Some(to_ticks(now.sec * 1000000000 + now.nsec as i64, self.nps))
+    }
+}
+
+/// A clock only moved by hand, for tests.
+#[derive(Default)]
+pub struct MockClock {
+    now: Mutex<Option<u64>>,
+}
+
+impl MockClock {
+    pub fn new(now: u64) -> Self {
+        MockClock { now: Mutex::new(Some(now)) }
+    }
+
+    pub fn set(&self, now: u64) {
+        *self.now.lock() = Some(now);
+    }
+
+    pub fn advance(&self, ticks: u64) {
+        let mut now = self.now.lock();
+        *now = Some(now.unwrap_or(0) + ticks);
+    }
+
+    /// Make the clock unavailable, like an unreachable NTP server.
+    pub fn fail(&self) {
+        *self.now.lock() = None;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Option<u64> {
+        *self.now.lock()
+    }
+}
+
+/// Build the clock selected in the config.
+pub fn from_config(config: &SleepyConfig) -> Arc<Clock> {
+    match config.clock {
+        ClockSource::Ntp => NtpClock::start(config.ntp_servers.clone(), config.nps),
+        ClockSource::System => Arc::new(SystemClock::new(config.nps)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_moves_by_hand() {
+        let clock = MockClock::new(10);
+        assert_eq!(clock.now(), Some(10));
+        clock.advance(5);
+        assert_eq!(clock.now(), Some(15));
+        clock.fail();
+        assert_eq!(clock.now(), None);
+        clock.set(3);
+        assert_eq!(clock.now(), Some(3));
+    }
+
+    #[test]
+    fn outliers_are_rejected() {
+        let second = 1000000000;
+        assert_eq!(median(&[]), None);
+        assert_eq!(median(&[3, 1, 2]), Some(2));
+        assert_eq!(median(&[4, 1, 2, 3]), Some(2));
+
+        let offsets = vec![10 * second, 2 * second, 2 * second + 1000, 2 * second - 1000, -5 * second];
+        assert_eq!(reject_outliers(&offsets), vec![2 * second, 2 * second + 1000, 2 * second - 1000]);
+        assert_eq!(offset_and_delay(100, 1100, 1120, 140), (990, 20));
+    }
+
+    #[test]
+    fn ntp_clock_tracks_median_offset() {
+        let clock = NtpClock::new(Vec::new(), 10);
+        assert_eq!(clock.now(), None);
+        assert!(!clock.health().unwrap().healthy);
+
+        let second = 1000000000;
+        let local = local_nanos();
+        clock.update(&[60 * second, 60 * second, 0], local - 10 * second);
+        clock.update(&[61 * second, 61 * second], local);
+        let health = clock.health().unwrap();
+        assert_eq!(health.offset, Some(61 * second));
+        assert_eq!(health.drift, Some(second / 10));
+        assert_eq!((health.answered, health.accepted), (2, 2));
+        assert!(health.healthy);
+
+        let expected = (local_nanos() + 61 * second) / (second / 10);
+        let now = clock.now().unwrap() as i64;
+        assert!(now >= expected - 1 && now <= expected + 1);
+    }
+
+    #[test]
+    fn ntp_clock_starts_unsynced() {
+        // the discard port never answers.
+        let begin = time::get_time();
+        let clock = NtpClock::start(vec!["127.0.0.1:9".to_string()], 10);
+        assert!(time::get_time() - begin < time::Duration::seconds(1));
+        assert_eq!(clock.now(), None);
+        let health = clock.health().unwrap();
+        assert_eq!((health.servers, health.last_sync_age, health.healthy), (1, None, false));
+    }
+
+    #[test]
+    fn system_clock_counts_ticks() {
+        let secs = time::now().to_timespec().sec as u64;
+        let now = SystemClock::new(10).now().unwrap();
+        assert!(now >= secs * 10 && now < (secs + 2) * 10);
+    }
+}
@@ -18,6 +18,10 @@
 
 use std::io;
 
+pub use std::fmt;
+pub use hash::*;
+pub use bytes::*;
+
 #[macro_export]
 macro_rules! vec_into {
     ( $( $x:expr ),* ) => {
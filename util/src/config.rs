@@ -6,10 +6,8 @@ use std::io::BufReader;
 use {H256, H512, U256};
 use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
-use std::thread;
-use std::sync::mpsc;
 use time;
-use ntp;
+use clock;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
@@ -26,6 +24,156 @@ pub struct Config {
     pub start_time: u64,
     pub ntp_servers: Vec<String>,
     pub buffer_size: u64,
+    #[serde(default)]
+    pub fork_choice: ForkChoiceRule,
+    #[serde(default = "default_finality_depth")]
+    pub finality_depth: u64,
+    #[serde(default)]
+    pub block_buffer: BlockBufferConfig,
+    #[serde(default)]
+    pub rpc: RpcConfig,
+    #[serde(default)]
+    pub clock: ClockSource,
+    #[serde(default)]
+    pub byzantine: ByzantineConfig,
+}
+
+/// Default confirmation depth after which a block is final.
+pub const DEFAULT_FINALITY_DEPTH: u64 = 20;
+
+fn default_finality_depth() -> u64 {
+    DEFAULT_FINALITY_DEPTH
+}
+
+/// Rule choosing between competing blocks at the same height.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForkChoiceRule {
+    /// Lowest lottery proof value wins.
+    LowestProof,
+    /// Earliest timestamp wins, ties broken by the lowest proof value.
+    EarliestTimestamp,
+}
+
+impl Default for ForkChoiceRule {
+    fn default() -> Self {
+        ForkChoiceRule::LowestProof
+    }
+}
+
+/// Limits of the buffers keeping blocks with unknown parent or future timestamp.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct BlockBufferConfig {
+    /// Maximum number of blocks in each buffer.
+    pub max_blocks: usize,
+    /// Maximum encoded size of the blocks in each buffer.
+    pub max_bytes: usize,
+    /// Maximum number of blocks from a single peer in each buffer.
+    pub max_per_peer: usize,
+    /// Seconds to wait for the parent of a buffered block.
+    pub orphan_timeout: u64,
+}
+
+impl Default for BlockBufferConfig {
+    fn default() -> Self {
+        BlockBufferConfig {
+            max_blocks: 1024,
+            max_bytes: 16 * 1024 * 1024,
+            max_per_peer: 256,
+            orphan_timeout: 60,
+        }
+    }
+}
+
+/// Where the node reads the time from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClockSource {
+    /// Ask the `ntp_servers`.
+    Ntp,
+    /// The local clock, for testnets without internet access.
+    System,
+}
+
+impl Default for ClockSource {
+    fn default() -> Self {
+        ClockSource::Ntp
+    }
+}
+
+/// How the node misbehaves, for Byzantine testing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Behaviour {
+    Honest,
+    /// Keep mined blocks private, release them together later.
+    Withhold,
+    /// Mine on an ancestor of the best block.
+    StaleParent,
+    /// Sign a second block for every slot won.
+    Equivocate,
+    /// Stamp mined blocks ahead of the clock.
+    FutureTimestamp,
+    /// Send blocks with unknown parents along with every mined block.
+    OrphanFlood,
+}
+
+impl Default for Behaviour {
+    fn default() -> Self {
+        Behaviour::Honest
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ByzantineConfig {
+    pub behaviour: Behaviour,
+    /// Blocks withheld before they are released.
+    pub withhold_blocks: u64,
+    /// Depth below the best block of the stale parent.
+    pub stale_depth: u64,
+    /// Ticks ahead of the clock of future timestamps.
+    pub future_ticks: u64,
+    /// Orphans sent along with each mined block.
+    pub orphans: u64,
+}
+
+impl Default for ByzantineConfig {
+    fn default() -> Self {
+        ByzantineConfig {
+            behaviour: Behaviour::Honest,
+            withhold_blocks: 3,
+            stale_depth: 2,
+            future_ticks: 100,
+            orphans: 16,
+        }
+    }
+}
+
+/// JSON-RPC HTTP server and WebSocket push.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RpcConfig {
+    /// Serve JSON-RPC on `port`.
+    pub enable: bool,
+    pub listen_address: String,
+    pub port: u64,
+    /// Push events over WebSocket on `ws_port`, independently of `enable`.
+    pub ws_enable: bool,
+    pub ws_port: u64,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        RpcConfig {
+            enable: false,
+            listen_address: "127.0.0.1".to_string(),
+            port: 1337,
+            ws_enable: false,
+            ws_port: 1338,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -133,33 +281,7 @@ impl SleepyConfig {
     }
 
     pub fn ntp_timestamp(&self) ->i64 {
-        let (tx, rx) = mpsc::channel();
-        let address = self.ntp_servers.clone();
-        let len = address.len();
-        for addr in address {
-            let tx = tx.clone();
-
-            thread::spawn(move || {
-                let time = match ntp::request(addr) {
-                    Ok(res) => {
-                        let t = time::Timespec::from(res.transmit_time);
-                        t.sec * 1000000000 + t.nsec as i64
-                    },
-                    _ => 0,
-                };
-                let _ = tx.send(time);
-            });
-        }
-        
-        let mut r: i64 = 0;
-        for _ in 0..len {
-            if let Ok(t) = rx.recv() {
-                if t != 0 && r == 0 {
-                    r = t
-                }
-            }
-        }
-        r
+        clock::ntp_timestamp(&self.ntp_servers)
     }
 }
 
@@ -183,6 +305,20 @@ mod test {
             signer_private_key = "5a39ed1020c04d4d84539975b893a4e7c53eab6c2965db8bc3468093a31bc5ae"
             ntp_servers = ["s1a.time.edu.cn:123", "cn.ntp.org.cn:123" ]
             buffer_size = 5
+            fork_choice = "earliest_timestamp"
+            clock = "system"
+
+            [block_buffer]
+            max_blocks = 100
+
+            [rpc]
+            enable = true
+            port = 2337
+            ws_port = 2338
+
+            [byzantine]
+            behaviour = "stale_parent"
+            stale_depth = 4
             
             [[peers]]
             id_card = 1
@@ -206,6 +342,21 @@ mod test {
         let config = SleepyConfig {config: value, public_keys: HashMap::new()};
         println!("{:?}", config);
         assert_eq!(config.port, 40000);
+        assert_eq!(config.fork_choice, ForkChoiceRule::EarliestTimestamp);
+        assert_eq!(config.clock, ClockSource::System);
+        assert_eq!(config.finality_depth, DEFAULT_FINALITY_DEPTH);
+        assert_eq!(config.block_buffer.max_blocks, 100);
+        assert_eq!(config.block_buffer.max_per_peer, BlockBufferConfig::default().max_per_peer);
+        assert_eq!(config.byzantine.behaviour, Behaviour::StaleParent);
+        assert_eq!(config.byzantine.stale_depth, 4);
+        assert_eq!(config.byzantine.orphans, ByzantineConfig::default().orphans);
+        assert_eq!(config.rpc, RpcConfig {
+            enable: true,
+            listen_address: "127.0.0.1".to_string(),
+            port: 2337,
+            ws_enable: false,
+            ws_port: 2338,
+        });
 
         let _ = config.ntp_now();
         thread::sleep(Duration::from_millis(100));
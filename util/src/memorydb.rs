@@ -0,0 +1,197 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Reference-counted memory-based `HashDB` implementation.
+
+use std::mem;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use hash::H256;
+use sha3::{Hashable, SHA3_NULL_RLP};
+use hashdb::{HashDB, DBValue};
+use rlp::NULL_RLP;
+
+/// Reference-counted memory-based `HashDB` implementation.
+///
+/// Use `new()` to create a new database. Insert items with `insert()`, remove items
+/// with `remove()`, check for existence with `contains()` and lookup a hash to derive
+/// the data with `get()`. Clear with `clear()` and purge the portions of the data
+/// that have no references with `purge()`.
+#[derive(Default, Clone, PartialEq)]
+pub struct MemoryDB {
+    data: HashMap<H256, (DBValue, i32)>,
+}
+
+impl MemoryDB {
+    /// Create a new instance of the memory DB.
+    pub fn new() -> MemoryDB {
+        MemoryDB { data: HashMap::new() }
+    }
+
+    /// Clear all data from the database.
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    /// Purge all zero-referenced data from the database.
+    pub fn purge(&mut self) {
+        self.data.retain(|_, &mut (_, rc)| rc != 0);
+    }
+
+    /// Return the internal map of hashes to data, clearing the current state.
+    pub fn drain(&mut self) -> HashMap<H256, (DBValue, i32)> {
+        mem::replace(&mut self.data, HashMap::new())
+    }
+
+    /// Grab the raw information associated with a key. Returns None if the key
+    /// doesn't exist.
+    ///
+    /// Even when Some is returned, the data is only guaranteed to be useful
+    /// when the refs > 0.
+    pub fn raw(&self, key: &H256) -> Option<(DBValue, i32)> {
+        if key == &SHA3_NULL_RLP {
+            return Some((DBValue::from_slice(&NULL_RLP), 1));
+        }
+        self.data.get(key).cloned()
+    }
+
+    /// Consolidate all the entries of `other` into `self`.
+    pub fn consolidate(&mut self, mut other: Self) {
+        for (key, (value, rc)) in other.drain() {
+            match self.data.entry(key) {
+                Entry::Occupied(mut entry) => {
+                    if entry.get().1 < 0 {
+                        entry.get_mut().0 = value;
+                    }
+
+                    entry.get_mut().1 += rc;
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert((value, rc));
+                }
+            }
+        }
+    }
+}
+
+impl HashDB for MemoryDB {
+    fn keys(&self) -> HashMap<H256, i32> {
+        self.data
+            .iter()
+            .filter_map(|(k, v)| if v.1 != 0 { Some((*k, v.1)) } else { None })
+            .collect()
+    }
+
+    fn get(&self, key: &H256) -> Option<DBValue> {
+        if key == &SHA3_NULL_RLP {
+            return Some(DBValue::from_slice(&NULL_RLP));
+        }
+
+        match self.data.get(key) {
+            Some(&(ref d, rc)) if rc > 0 => Some(d.clone()),
+            _ => None,
+        }
+    }
+
+    fn contains(&self, key: &H256) -> bool {
+        if key == &SHA3_NULL_RLP {
+            return true;
+        }
+
+        match self.data.get(key) {
+            Some(&(_, x)) if x > 0 => true,
+            _ => false,
+        }
+    }
+
+    fn insert(&mut self, value: &[u8]) -> H256 {
+        if value == &NULL_RLP {
+            return SHA3_NULL_RLP.clone();
+        }
+        let key = value.sha3();
+        match self.data.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let &mut (ref mut old_value, ref mut rc) = entry.get_mut();
+                if *rc <= 0 {
+                    *old_value = DBValue::from_slice(value);
+                }
+                *rc += 1;
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((DBValue::from_slice(value), 1));
+            }
+        }
+        key
+    }
+
+    fn emplace(&mut self, key: H256, value: DBValue) {
+        if &*value == &NULL_RLP {
+            return;
+        }
+
+        match self.data.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let &mut (ref mut old_value, ref mut rc) = entry.get_mut();
+                if *rc <= 0 {
+                    *old_value = value;
+                }
+                *rc += 1;
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((value, 1));
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &H256) {
+        if key == &SHA3_NULL_RLP {
+            return;
+        }
+
+        match self.data.entry(*key) {
+            Entry::Occupied(mut entry) => {
+                let &mut (_, ref mut rc) = entry.get_mut();
+                *rc -= 1;
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((DBValue::new(), -1));
+            }
+        }
+    }
+}
+
+#[test]
+fn memorydb_remove_and_purge() {
+    let hello_bytes = b"Hello world!";
+    let hello_key = hello_bytes.sha3();
+
+    let mut m = MemoryDB::new();
+    m.remove(&hello_key);
+    assert_eq!(m.raw(&hello_key).unwrap().1, -1);
+    m.purge();
+    assert_eq!(m.raw(&hello_key).unwrap().1, -1);
+    m.insert(hello_bytes);
+    assert_eq!(m.raw(&hello_key).unwrap().1, 0);
+    m.purge();
+    assert_eq!(m.raw(&hello_key), None);
+
+    let mut m = MemoryDB::new();
+    assert!(m.get(&hello_key).is_none());
+    m.insert(hello_bytes);
+    assert_eq!(m.get(&hello_key).unwrap().to_vec(), hello_bytes.to_vec());
+    m.remove(&hello_key);
+    assert!(m.get(&hello_key).is_none());
+}
@@ -35,6 +35,72 @@ pub fn complete_merkle_root_raw(input: Vec<H256>) -> H256 {
     gen_merkle_root(input)
 }
 
+/// A sibling hash on the path from a leaf up to the merkle root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofNode {
+    /// Whether the sibling is merged from the left.
+    pub is_left: bool,
+    pub hash: H256,
+}
+
+/// Generates the merkle branch of the leaf at `index`.
+///
+/// The branch is ordered from the leaf up to the root, so it can be checked
+/// with `verify_merkle_proof` against the root from `complete_merkle_root_raw`.
+pub fn complete_merkle_proof_raw(input: Vec<H256>, index: usize) -> Option<Vec<ProofNode>> {
+    let inlen = input.len();
+    if index >= inlen {
+        return None;
+    }
+
+    let lwlen = lowest_children_len(inlen);
+    let mut proof = Vec::new();
+    let mut i: usize = 0;
+    let mut nodes = Vec::new();
+
+    while i < lwlen {
+        nodes.push(merge(&input[i], &input[i + 1]));
+        i += 2;
+    }
+
+    for j in i..inlen {
+        nodes.push(input[j]);
+    }
+
+    // leaves below the perfect tree are merged pairwise first
+    let mut pos = match index < lwlen {
+        true => {
+            proof.push(sibling(&input, index));
+            index / 2
+        }
+        false => index - lwlen / 2,
+    };
+
+    while nodes.len() > 1 {
+        proof.push(sibling(&nodes, pos));
+        nodes = nodes.chunks(2).map(|pair| merge(&pair[0], &pair[1])).collect();
+        pos /= 2;
+    }
+
+    Some(proof)
+}
+
+/// Checks that `leaf` is included under `root` through `proof`.
+pub fn verify_merkle_proof(root: &H256, leaf: &H256, proof: &[ProofNode]) -> bool {
+    let hash = proof.iter().fold(*leaf, |hash, node| match node.is_left {
+        true => merge(&node.hash, &hash),
+        false => merge(&hash, &node.hash),
+    });
+    hash == *root
+}
+
+fn sibling(nodes: &[H256], pos: usize) -> ProofNode {
+    match pos % 2 == 0 {
+        true => ProofNode { is_left: false, hash: nodes[pos + 1] },
+        false => ProofNode { is_left: true, hash: nodes[pos - 1] },
+    }
+}
+
 fn lowest_children_len(amount: usize) -> usize {
     let mut n: usize = 1;
     let mut r: usize = 0;
@@ -90,14 +156,17 @@ fn merge(left: &H256, right: &H256) -> H256 {
 }
 
 #[cfg(test)]
-#[cfg(feature = "sha3hash")]
 mod tests {
-    use super::complete_merkle_root;
-    use super::complete_merkle_root_raw;
-    use H256;
+    use super::*;
+    #[cfg(feature = "sha3hash")]
     use std::str::FromStr;
 
+    fn leaves(n: u8) -> Vec<H256> {
+        (0..n).map(|i| vec![i].sha3()).collect()
+    }
+
     #[test]
+    #[cfg(feature = "sha3hash")]
     fn complete_test() {
         assert_eq!(complete_merkle_root(vec![b"A".to_vec(), b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_vec()]), H256::from_str("9bd41e0d43f4ec7a703edc2eb9fbb4106e1bc2a845e9ee1d4f3f4cf99b8549e6").unwrap());
 
@@ -120,6 +189,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "sha3hash")]
     fn complete_test_raw() {
         assert_eq!(
             complete_merkle_root_raw(vec![
@@ -134,4 +204,24 @@ mod tests {
 
     }
 
+    #[test]
+    fn proof_for_every_leaf() {
+        for n in 1..18 {
+            let input = leaves(n);
+            let root = complete_merkle_root_raw(input.clone());
+            for i in 0..input.len() {
+                let proof = complete_merkle_proof_raw(input.clone(), i).unwrap();
+                assert!(verify_merkle_proof(&root, &input[i], &proof));
+            }
+        }
+    }
+
+    #[test]
+    fn proof_rejects_wrong_leaf() {
+        let input = leaves(5);
+        let root = complete_merkle_root_raw(input.clone());
+        let proof = complete_merkle_proof_raw(input.clone(), 3).unwrap();
+        assert!(!verify_merkle_proof(&root, &input[2], &proof));
+        assert_eq!(complete_merkle_proof_raw(input, 5), None);
+    }
 }